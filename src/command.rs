@@ -1,77 +1,704 @@
-use std::io::{self, Write};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Once};
+use std::time::Duration;
 
-use crate::model::MessageParts;
+use crate::model::{
+    estimate_tokens, estimate_tokens_from_bytes, ArtifactRef, HistoryWindow, Message, MessageParts,
+    Messages, RichMessage, TokenUsage, WorkspaceContext,
+};
 use crate::{
+    completions::{self, Shell},
     db::Db,
+    lang::extension_for_language,
     model::{Conversation, Result},
-    query::Queryable,
-    repl::make_readline,
+    query::{
+        clamp_sampling_parameter, Queryable, ResponseChunk, StopReason, WithTemperature,
+        MAX_TEMPERATURE,
+    },
+    render::{
+        export_markdown, format_artifact_content, format_message, maybe_page, parse_message_parts,
+        RenderOptions, Spinner, StreamRenderer,
+    },
+    repl::{edit_in_editor, join_continued_lines, make_readline},
 };
+use chrono::{DateTime, Local};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command as ClapCommand, ValueHint};
 use colored::Colorize;
-use regex::Regex;
+use log::LevelFilter;
+use notify::{RecursiveMode, Watcher};
+use regex::RegexBuilder;
 use rustyline::error::ReadlineError;
-use termimad::crossterm::style::Stylize;
-use termimad::{MadSkin, terminal_size};
+use termimad::MadSkin;
+
+const DEFAULT_SUMMARIZE_KEEP: usize = 1;
+
+// Sent after a truncated response to have the model pick up where it stopped.
+const CONTINUE_PROMPT: &str =
+    "Continue your previous response exactly where it stopped, without repeating any of it.";
+
+const SUMMARIZE_PROMPT: &str = "Summarize our conversation so far concisely, so that the summary can replace it. \
+    Keep the decisions made, requirements, open questions, and the identifiers of any artifacts and the files they \
+    relate to. Don't reproduce the artifacts themselves.";
+
+// The REPL's own commands, which the parser doesn't know about, for `!help`.
+const REPL_HELP: &str = "REPL COMMANDS:
+    temp <temperature>    Set the temperature for this session (`!temp reset` restores the default)
+    edit                  Edit your last message in $EDITOR and resubmit it
 
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style, ThemeSet};
-use syntect::parsing::SyntaxSet;
-use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+Input without a leading `!` is sent as a query. Exit with Ctrl+C or Ctrl+D.";
+
+// Appended to a response that the user stopped with Ctrl-C, so it is clearly partial in the history.
+const INTERRUPTED_MARKER: &str = "\n\n[Response interrupted]";
+// Appended to a response whose stream failed partway through.
+const INCOMPLETE_MARKER: &str = "\n\n[Response incomplete: the stream failed]";
+
+static STREAMING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_INTERRUPT_HANDLER: Once = Once::new();
+
+/// While alive, Ctrl-C stops the streaming response instead of exiting. A second Ctrl-C still exits.
+struct InterruptGuard;
+
+impl InterruptGuard {
+    fn new() -> InterruptGuard {
+        INSTALL_INTERRUPT_HANDLER.call_once(|| {
+            let installed = ctrlc::set_handler(|| {
+                if STREAMING.load(Ordering::SeqCst) && !INTERRUPTED.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                process::exit(130);
+            });
+            if let Err(e) = installed {
+                log::warn!("Could not install the Ctrl-C handler: {e}");
+            }
+        });
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        STREAMING.store(true, Ordering::SeqCst);
+        InterruptGuard
+    }
+
+    fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        STREAMING.store(false, Ordering::SeqCst);
+    }
+}
 
 #[derive(Debug)]
 pub enum CliCmd {
-    NewConversation { conversation_id: String },
-    AddWorkspaceContext { paths: Vec<String> },
+    NewConversation {
+        conversation_id: String,
+    },
+    AddWorkspaceContext {
+        paths: Vec<String>,
+        /// Add a listing of each directory instead of its files.
+        tree: bool,
+        /// Fetch URLs again rather than using their cached contents.
+        refresh: bool,
+        /// Shell commands whose output to add.
+        commands: Vec<String>,
+        /// Add the unstaged changes in the repository.
+        diff: bool,
+        /// Add the staged changes in the repository.
+        staged: bool,
+    },
+    RemoveWorkspaceContext {
+        /// Indices, exact contexts or glob patterns, as shown by `ls`.
+        selectors: Vec<String>,
+        /// Remove all context instead.
+        all: bool,
+    },
     Repl,
-    Query { query: String },
+    Query {
+        query: String,
+        /// Print the request that would be sent instead of sending it.
+        dry_run: bool,
+    },
     Clear,
     ListWorkspaceContext,
     History,
+    /// Prints a conversation, by default the current one, then each message added to it until interrupted.
+    Watch {
+        conversation_id: Option<String>,
+    },
+    ListConversations,
+    SwitchConversation {
+        conversation_id: String,
+    },
+    SaveArtifact {
+        identifier: String,
+        path: Option<String>,
+    },
+    ShowArtifact {
+        identifier: String,
+    },
+    /// Copies an artifact to the clipboard, or the most recent artifact if no identifier is given.
+    CopyArtifact {
+        identifier: Option<String>,
+    },
+    ExportArtifacts {
+        dir: String,
+    },
+    /// Prints the current conversation as a document.
+    Export {
+        format: ExportFormat,
+    },
+    RenameConversation {
+        new_id: String,
+    },
+    /// Copies the current conversation, with its messages and context, to a new conversation and switches to it.
+    BranchConversation {
+        new_id: String,
+    },
+    DeleteConversation {
+        conversation_id: String,
+    },
+    Retry,
+    Continue,
+    /// Removes the last message and its response, so the context first sent with it is sent again.
+    Undo,
+    /// Replaces all but the last `keep` exchanges with a summary of them, generated by the model.
+    Summarize {
+        keep: usize,
+    },
+    Search {
+        query: String,
+    },
+    Tokens,
+    Refresh,
+    /// Sets the conversation's system prompt, or resets it to the default if `None`.
+    SetSystemPrompt {
+        prompt: Option<String>,
+    },
+    /// Prints a completion script for a shell.
+    Completions {
+        shell: Shell,
+    },
+    /// Prints the settings in effect, and where each was taken from. The settings are resolved by the binary, so
+    /// this is handled there.
+    Config,
+}
+
+#[derive(Debug)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+/// Global flags, given before or after the command name (e.g. `claippy --model <id> q ...`).
+#[derive(Debug, Default)]
+pub struct CliFlags {
+    pub model_id: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Sequences that end the response, from repeated `--stop` flags.
+    pub stop_sequences: Vec<String>,
+    pub system_prompt_file: Option<String>,
+    /// The width to wrap output to, instead of the terminal's width.
+    pub width: Option<u16>,
+    pub no_color: bool,
+    pub json: bool,
+    pub raw: bool,
+    pub no_artifact_headers: bool,
+    pub line_numbers: bool,
+    /// The log level from `-v` (repeatable) or `-q`, if either was given.
+    pub log_level: Option<LevelFilter>,
+}
+
+impl CliFlags {
+    fn from_matches(matches: &ArgMatches) -> CliFlags {
+        CliFlags {
+            model_id: matches.get_one::<String>("model").cloned(),
+            temperature: matches.get_one::<f32>("temperature").copied(),
+            top_p: matches.get_one::<f32>("top-p").copied(),
+            stop_sequences: matches
+                .get_many::<String>("stop")
+                .map(|stops| stops.cloned().collect())
+                .unwrap_or_default(),
+            system_prompt_file: matches.get_one::<String>("system-prompt-file").cloned(),
+            width: matches.get_one::<u16>("width").copied(),
+            no_color: matches.get_flag("no-color"),
+            json: matches.get_flag("json"),
+            raw: matches.get_flag("raw"),
+            no_artifact_headers: matches.get_flag("no-artifact-headers"),
+            line_numbers: matches.get_flag("line-numbers"),
+            log_level: match (matches.get_flag("quiet"), matches.get_count("verbose")) {
+                (true, _) => Some(LevelFilter::Off),
+                (false, 0) => None,
+                (false, 1) => Some(LevelFilter::Info),
+                (false, 2) => Some(LevelFilter::Debug),
+                (false, _) => Some(LevelFilter::Trace),
+            },
+        }
+    }
 }
 
 pub enum CmdOutput {
     Done,
     Message(String),
+    Json(serde_json::Value),
+}
+
+fn flag(name: &'static str, help: &'static str) -> Arg<'static> {
+    Arg::new(name)
+        .long(name)
+        .action(ArgAction::SetTrue)
+        .help(help)
+}
+
+// A positional argument that takes the rest of the command line, e.g. the words of a query.
+fn words(name: &'static str, help: &'static str) -> Arg<'static> {
+    Arg::new(name)
+        .value_name(name)
+        .multiple_values(true)
+        .allow_hyphen_values(true)
+        .help(help)
+}
+
+/// The command line interface: the global flags, and a subcommand for each command. With no command, claippy
+/// starts the REPL.
+pub fn cli() -> ClapCommand<'static> {
+    commands()
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A coding assistant for the terminal, with the files you're working on as context")
+        .subcommand_required(false)
+        .args([
+            Arg::new("model")
+                .long("model")
+                .value_name("ID")
+                .global(true)
+                .help("The model to query"),
+            Arg::new("temperature")
+                .long("temperature")
+                .value_name("VALUE")
+                .value_parser(value_parser!(f32))
+                .allow_hyphen_values(true)
+                .global(true)
+                .help("The sampling temperature, between 0 and 2 (0 and 1 for Bedrock)"),
+            Arg::new("top-p")
+                .long("top-p")
+                .value_name("VALUE")
+                .value_parser(value_parser!(f32))
+                .allow_hyphen_values(true)
+                .global(true)
+                .help("The top_p sampling parameter, between 0 and 1"),
+            Arg::new("stop")
+                .long("stop")
+                .value_name("SEQUENCE")
+                .action(ArgAction::Append)
+                .allow_hyphen_values(true)
+                .global(true)
+                .help("A sequence that ends the response when generated. Can be repeated"),
+            Arg::new("system-prompt-file")
+                .long("system-prompt-file")
+                .value_name("PATH")
+                .value_hint(ValueHint::FilePath)
+                .global(true)
+                .help("Read the system prompt from a file"),
+            Arg::new("width")
+                .long("width")
+                .value_name("COLUMNS")
+                .value_parser(value_parser!(u16).range(1..))
+                .global(true)
+                .help("Wrap output to this many columns, instead of the terminal's width"),
+            flag("no-color", "Disable colors and styling").global(true),
+            flag("json", "Print results as JSON").global(true),
+            flag(
+                "raw",
+                "Print responses as the model sent them, without rendering",
+            )
+            .global(true),
+            flag(
+                "no-artifact-headers",
+                "Print artifacts without the line naming each one, so they copy cleanly",
+            )
+            .global(true),
+            flag("line-numbers", "Number the lines of artifacts").global(true),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .global(true)
+                .help("Log requests and timings. Repeat for more detail: -vv for debug, -vvv for trace"),
+            flag("quiet", "Don't log anything, not even errors")
+                .short('q')
+                .conflicts_with("verbose")
+                .global(true),
+        ])
+}
+
+// The commands, without the global flags. The REPL parses its `!` commands with these.
+fn commands() -> ClapCommand<'static> {
+    ClapCommand::new("claippy")
+        .subcommand_required(true)
+        .subcommands([
+            ClapCommand::new("query")
+                .visible_alias("q")
+                .about("Send a one-off query. Piped input is appended to it")
+                .trailing_var_arg(true)
+                .arg(flag(
+                    "dry-run",
+                    "Print the request that would be sent instead of sending it",
+                ))
+                .arg(words("query", "The query")),
+            ClapCommand::new("new")
+                .visible_alias("n")
+                .about("Create a new conversation")
+                .arg(words("name", "The conversation's name")),
+            ClapCommand::new("add")
+                .visible_alias("a")
+                .about("Add files, directories, globs or URLs as context")
+                .arg(flag(
+                    "tree",
+                    "Add a listing of each directory instead of its files",
+                ))
+                .arg(flag(
+                    "refresh",
+                    "Fetch URLs again instead of using their cached contents",
+                ))
+                .arg(
+                    Arg::new("cmd")
+                        .long("cmd")
+                        .value_name("COMMAND")
+                        .action(ArgAction::Append)
+                        .help("Add the output of a shell command, run when it's next sent. Add it again or use `refresh` to rerun it. Can be repeated"),
+                )
+                .arg(flag("diff", "Add the unstaged changes, as `git diff` shows them"))
+                .arg(flag(
+                    "staged",
+                    "Add the staged changes, as `git diff --staged` shows them",
+                ))
+                .arg(
+                    Arg::new("paths")
+                        .value_name("PATH")
+                        .multiple_values(true)
+                        .value_hint(ValueHint::AnyPath)
+                        .help("Files, directories, quoted globs or URLs"),
+                ),
+            ClapCommand::new("remove")
+                .visible_alias("rm")
+                .about("Remove context by index, path or glob")
+                .arg(flag("all", "Remove all context"))
+                .arg(
+                    Arg::new("selectors")
+                        .value_name("SELECTOR")
+                        .multiple_values(true)
+                        .value_hint(ValueHint::AnyPath)
+                        .required_unless_present("all")
+                        .help("Indices as listed by `ls`, paths or quoted glob patterns"),
+                ),
+            ClapCommand::new("clear").about("Clear the current conversation history"),
+            ClapCommand::new("ls").about("List the current context"),
+            ClapCommand::new("repl").about("Start an interactive REPL session"),
+            ClapCommand::new("history").about("Display the conversation history"),
+            ClapCommand::new("watch")
+                .about("Follow a conversation, printing messages as they're added, until Ctrl-C")
+                .arg(Arg::new("id").help("The conversation's id, by default the current one")),
+            ClapCommand::new("conversations")
+                .visible_alias("convs")
+                .about("List saved conversations, most recent first"),
+            ClapCommand::new("switch")
+                .visible_alias("sw")
+                .about("Make an existing conversation the current one")
+                .arg(Arg::new("id").required(true).help("The conversation's id")),
+            ClapCommand::new("rename")
+                .about("Rename the current conversation")
+                .arg(words("name", "The new name").required(true)),
+            ClapCommand::new("branch")
+                .about("Copy the current conversation to a new one and switch to it")
+                .arg(words("name", "The new conversation's name").required(true)),
+            ClapCommand::new("delete")
+                .visible_alias("del")
+                .about("Delete a conversation")
+                .arg(Arg::new("id").required(true).help("The conversation's id")),
+            ClapCommand::new("retry").about("Discard the last response and generate a new one"),
+            ClapCommand::new("continue").about("Continue a response that was cut off"),
+            ClapCommand::new("undo").about("Remove the last message and its response"),
+            ClapCommand::new("summarize")
+                .about("Replace earlier messages with a summary of them")
+                .arg(
+                    Arg::new("keep")
+                        .value_parser(value_parser!(usize))
+                        .help("How many of the latest exchanges to keep"),
+                ),
+            ClapCommand::new("search")
+                .about("Search the messages of all conversations")
+                .trailing_var_arg(true)
+                .arg(words("query", "The text to search for").required(true)),
+            ClapCommand::new("tokens").about("Estimate how many tokens the next query would send"),
+            ClapCommand::new("refresh").about("Resend all context with the next message"),
+            ClapCommand::new("system")
+                .about("Replace the system prompt for the current conversation")
+                .trailing_var_arg(true)
+                .arg(flag("reset", "Restore the default system prompt"))
+                .arg(
+                    words("prompt", "The new system prompt")
+                        .required_unless_present("reset")
+                        .conflicts_with("reset"),
+                ),
+            ClapCommand::new("show")
+                .about("Print the content of an artifact")
+                .arg(
+                    Arg::new("identifier")
+                        .required(true)
+                        .help("The artifact's identifier"),
+                ),
+            ClapCommand::new("copy")
+                .about("Copy an artifact to the clipboard, or the most recent artifact")
+                .arg(Arg::new("identifier").help("The artifact's identifier")),
+            ClapCommand::new("save")
+                .about("Save an artifact to a file")
+                .arg(
+                    Arg::new("identifier")
+                        .required(true)
+                        .help("The artifact's identifier"),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_hint(ValueHint::FilePath)
+                        .help("Where to save it, by default named after the identifier"),
+                ),
+            ClapCommand::new("export-artifacts")
+                .about("Save the latest version of every artifact to a directory")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .value_hint(ValueHint::DirPath)
+                        .help("The directory to save them to"),
+                ),
+            ClapCommand::new("export")
+                .about("Print the current conversation as markdown or JSON")
+                .arg(
+                    Arg::new("format")
+                        .value_parser(["markdown", "md", "json"])
+                        .default_value("markdown")
+                        .help("The format to print"),
+                ),
+            ClapCommand::new("config")
+                .about("Print the settings in effect, and where each was taken from"),
+            ClapCommand::new("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::new("shell")
+                        .value_parser(["bash", "zsh", "fish"])
+                        .required(true)
+                        .help("The shell to complete for"),
+                ),
+        ])
 }
 
 impl CliCmd {
-    pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<CliCmd> {
-        let cmd = args.next().unwrap_or("repl".to_owned());
-
-        let cmd = match cmd.as_str() {
-            "query" | "q" => Ok(CliCmd::Query {
-                query: args.collect::<Vec<String>>().join(" "),
-            }),
-            "new" | "n" => {
-                let conversation_id =
-                    Conversation::create_id(args.collect::<Vec<String>>().join("-"));
-                Ok(CliCmd::NewConversation { conversation_id })
-            }
-            "add" | "a" => Ok(CliCmd::AddWorkspaceContext {
-                paths: args.collect(),
-            }),
-            "clear" => Ok(CliCmd::Clear),
-            "ls" => Ok(CliCmd::ListWorkspaceContext),
-            "repl" => Ok(CliCmd::Repl),
-            "history" => Ok(CliCmd::History),
-            other => Err(format!("Unknown command: {other}")),
-        }?;
+    /// Parses a full command line, including the program name.
+    pub fn parse_cli(args: impl Iterator<Item = String>) -> Result<(CliFlags, CliCmd)> {
+        let matches = cli().try_get_matches_from(args)?;
+        Ok((
+            CliFlags::from_matches(&matches),
+            CliCmd::from_matches(&matches)?,
+        ))
+    }
+
+    /// Parses a REPL command and its arguments, given after its `!`, without the global flags.
+    pub fn parse_args(args: impl Iterator<Item = String>) -> Result<CliCmd> {
+        let matches = commands()
+            .name("claippy repl")
+            .no_binary_name(true)
+            .override_usage("!<COMMAND> [ARGS]")
+            .after_help(REPL_HELP)
+            .try_get_matches_from(args)?;
+        CliCmd::from_matches(&matches)
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<CliCmd> {
+        let Some((name, args)) = matches.subcommand() else {
+            return Ok(CliCmd::Repl);
+        };
+        let value = |id: &str| args.get_one::<String>(id).cloned();
+        let values = |id: &str| -> Vec<String> {
+            args.get_many::<String>(id)
+                .map(|values| values.cloned().collect())
+                .unwrap_or_default()
+        };
+
+        let cmd = match name {
+            "query" => {
+                let mut query = values("query").join(" ");
+                if let Some(piped) = read_piped_stdin()? {
+                    if !query.is_empty() {
+                        query.push_str("\n\n");
+                    }
+                    query.push_str(&format!("```\n{}\n```", piped.trim_end()));
+                }
+                CliCmd::Query {
+                    query,
+                    dry_run: args.get_flag("dry-run"),
+                }
+            }
+            "new" => CliCmd::NewConversation {
+                conversation_id: Conversation::create_id(values("name").join("-")),
+            },
+            "add" => CliCmd::AddWorkspaceContext {
+                paths: values("paths"),
+                tree: args.get_flag("tree"),
+                refresh: args.get_flag("refresh"),
+                commands: values("cmd"),
+                diff: args.get_flag("diff"),
+                staged: args.get_flag("staged"),
+            },
+            "remove" => CliCmd::RemoveWorkspaceContext {
+                selectors: values("selectors"),
+                all: args.get_flag("all"),
+            },
+            "clear" => CliCmd::Clear,
+            "ls" => CliCmd::ListWorkspaceContext,
+            "repl" => CliCmd::Repl,
+            "history" => CliCmd::History,
+            "watch" => CliCmd::Watch {
+                conversation_id: value("id"),
+            },
+            "conversations" => CliCmd::ListConversations,
+            "switch" => CliCmd::SwitchConversation {
+                conversation_id: value("id").unwrap_or_default(),
+            },
+            "show" => CliCmd::ShowArtifact {
+                identifier: value("identifier").unwrap_or_default(),
+            },
+            "copy" => CliCmd::CopyArtifact {
+                identifier: value("identifier"),
+            },
+            "save" => CliCmd::SaveArtifact {
+                identifier: value("identifier").unwrap_or_default(),
+                path: value("path"),
+            },
+            "export-artifacts" => CliCmd::ExportArtifacts {
+                dir: value("dir").unwrap_or_default(),
+            },
+            "export" => CliCmd::Export {
+                format: match value("format").as_deref() {
+                    Some("json") => ExportFormat::Json,
+                    _ => ExportFormat::Markdown,
+                },
+            },
+            "rename" => CliCmd::RenameConversation {
+                new_id: Conversation::create_id(values("name").join("-")),
+            },
+            "branch" => CliCmd::BranchConversation {
+                new_id: Conversation::create_id(values("name").join("-")),
+            },
+            "delete" => CliCmd::DeleteConversation {
+                conversation_id: value("id").unwrap_or_default(),
+            },
+            "search" => CliCmd::Search {
+                query: values("query").join(" "),
+            },
+            "tokens" => CliCmd::Tokens,
+            "refresh" => CliCmd::Refresh,
+            "system" => CliCmd::SetSystemPrompt {
+                prompt: Some(values("prompt").join(" ")).filter(|_| !args.get_flag("reset")),
+            },
+            "retry" => CliCmd::Retry,
+            "continue" => CliCmd::Continue,
+            "undo" => CliCmd::Undo,
+            "summarize" => CliCmd::Summarize {
+                keep: args
+                    .get_one::<usize>("keep")
+                    .copied()
+                    .unwrap_or(DEFAULT_SUMMARIZE_KEEP),
+            },
+            "config" => CliCmd::Config,
+            "completions" => CliCmd::Completions {
+                shell: value("shell")
+                    .as_deref()
+                    .and_then(Shell::parse)
+                    .ok_or("completions requires a shell: bash, zsh or fish")?,
+            },
+            other => return Err(format!("Unknown command: {other}").into()),
+        };
 
         Ok(cmd)
     }
 }
 
+/// Reads all of stdin if it is being piped to us (i.e. is not a TTY).
+/// Returns None when stdin is interactive or the piped input is empty.
+fn read_piped_stdin() -> Result<Option<String>> {
+    let mut stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+
+    let mut input = String::new();
+    stdin.read_to_string(&mut input)?;
+    if input.trim().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(input))
+    }
+}
+
 pub trait Command {
-    fn execute(self, model: &impl Queryable, db: &Db) -> Result<CmdOutput>;
+    fn execute(self, model: &impl Queryable, db: &Db, render: &RenderOptions) -> Result<CmdOutput>;
 }
 
 impl Command for CliCmd {
-    fn execute(self, model: &impl Queryable, db: &Db) -> Result<CmdOutput> {
+    fn execute(self, model: &impl Queryable, db: &Db, render: &RenderOptions) -> Result<CmdOutput> {
+        // Hold the lock while reading, modifying and writing conversations, so that concurrent claippy processes
+        // don't overwrite each other's changes. The REPL locks for each command it runs instead.
+        let _lock = match self {
+            Self::Repl
+            | Self::History
+            | Self::Watch { .. }
+            | Self::ListWorkspaceContext
+            | Self::ListConversations
+            | Self::Search { .. }
+            | Self::Tokens
+            | Self::ShowArtifact { .. }
+            | Self::CopyArtifact { .. }
+            | Self::Export { .. }
+            | Self::Completions { .. }
+            | Self::Config => None,
+            _ => Some(db.lock()?),
+        };
+
         match self {
-            Self::Query { query } => handle_query(model, query, db),
-            Self::Repl => handle_repl(model, db),
-            Self::AddWorkspaceContext { paths } => handle_add_workspace_contexts(db, paths),
+            Self::Query {
+                query,
+                dry_run: true,
+            } => handle_dry_run(model, query, db),
+            Self::Query { query, .. } => handle_query(model, query, db, render),
+            Self::Repl => handle_repl(model, db, render),
+            Self::RemoveWorkspaceContext { selectors, all } => {
+                handle_remove_workspace_contexts(db, &selectors, all)
+            }
+            Self::AddWorkspaceContext {
+                paths,
+                tree,
+                refresh,
+                commands,
+                diff,
+                staged,
+            } => {
+                let generated = GeneratedContexts {
+                    commands,
+                    diff,
+                    staged,
+                };
+                handle_add_workspace_contexts(db, paths, tree, refresh, generated)
+                    .map(CmdOutput::Message)
+            }
             Self::NewConversation { conversation_id } => {
+                // Ids are only unique to the second
+                if db.conversation_exists(&conversation_id) {
+                    return Err(format!("Conversation {conversation_id} already exists").into());
+                }
                 db.create_conversation(&conversation_id)?;
                 Ok(CmdOutput::Message(
                     "Created conversation ".to_owned() + &conversation_id,
@@ -85,224 +712,950 @@ impl Command for CliCmd {
                     "Cleared conversation ".to_owned() + &conversation.id,
                 ))
             }
-            Self::ListWorkspaceContext => {
+            Self::ListWorkspaceContext => handle_list_workspace_contexts(db),
+            Self::History => {
                 let conversation = db.read_current_conversation()?;
-                let contexts = conversation
-                    .seen_context
+                let skin = render.skin();
+                let mut history = String::new();
+                for message in &conversation.messages {
+                    history += &format_history_message(render, &skin, message);
+                }
+                history += &format!(
+                    "{}\n",
+                    format!("[Total: {}]", conversation.token_usage).dimmed()
+                );
+                maybe_page(render, &history)?;
+                Ok(CmdOutput::Done)
+            }
+            Self::Watch { conversation_id } => handle_watch(db, conversation_id, render),
+            Self::ListConversations => {
+                let conversations = db
+                    .list_conversations()?
                     .into_iter()
-                    .chain(conversation.unseen_context);
-                let context_display = "Current context:\n".to_owned()
-                    + &contexts
-                        .map(|c| c.to_string())
-                        .collect::<Vec<String>>()
-                        .join("\n");
-                Ok(CmdOutput::Message(context_display))
+                    .map(|c| {
+                        let modified: DateTime<Local> = c.modified.into();
+                        format!(
+                            "{}  {} messages  {}",
+                            c.id,
+                            c.message_count,
+                            modified.format("%Y-%m-%d %H:%M").to_string().dimmed()
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                Ok(CmdOutput::Message(
+                    "Conversations:\n".to_owned() + &conversations,
+                ))
             }
-            Self::History => {
+            Self::SwitchConversation { conversation_id } => {
+                db.switch_conversation(&conversation_id)?;
+                Ok(CmdOutput::Message(
+                    "Switched to conversation ".to_owned() + &conversation_id,
+                ))
+            }
+            Self::SaveArtifact { identifier, path } => {
                 let conversation = db.read_current_conversation()?;
-                let skin = MadSkin::default();
-                for message in conversation.as_messages() {
-                    println!("{}", message.role.blue().bold());
-                    let parts = parse_message_parts(message.content);
-                    println!("{}", format_message(&skin, &parts));
-                }
+                let artifact = find_artifact(&conversation, &identifier)?;
+                let path = path.unwrap_or_else(|| artifact_file_name(&artifact));
+                fs::write(&path, artifact_file_content(&artifact))?;
+                Ok(CmdOutput::Message(format!(
+                    "Saved artifact {identifier} to {path}"
+                )))
+            }
+            Self::ShowArtifact { identifier } => {
+                let conversation = db.read_current_conversation()?;
+                let artifact = find_artifact(&conversation, &identifier)?;
+                let content = artifact_file_content(&artifact);
+                Ok(CmdOutput::Message(format_artifact_content(
+                    render,
+                    artifact.language,
+                    content.trim_end_matches('\n'),
+                )))
+            }
+            Self::CopyArtifact { identifier } => handle_copy_artifact(db, identifier),
+            Self::ExportArtifacts { dir } => handle_export_artifacts(db, dir),
+            Self::Export { format } => {
+                let conversation = db.read_current_conversation()?;
+                let exported = match format {
+                    ExportFormat::Markdown => export_markdown(&conversation),
+                    ExportFormat::Json => serde_json::to_string_pretty(&conversation)?,
+                };
+                maybe_page(render, &exported)?;
                 Ok(CmdOutput::Done)
             }
+            Self::RenameConversation { new_id } => {
+                let old_id = db.rename_current_conversation(&new_id)?;
+                Ok(CmdOutput::Message(format!(
+                    "Renamed conversation {old_id} to {new_id}"
+                )))
+            }
+            Self::BranchConversation { new_id } => {
+                let old_id = db.branch_current_conversation(&new_id)?;
+                Ok(CmdOutput::Message(format!(
+                    "Branched conversation {old_id} to {new_id}"
+                )))
+            }
+            Self::DeleteConversation { conversation_id } => {
+                let message = match db.delete_conversation(&conversation_id)? {
+                    Some(current) => format!(
+                        "Deleted conversation {conversation_id}. Current conversation is now {current}"
+                    ),
+                    None => format!("Deleted conversation {conversation_id}"),
+                };
+                Ok(CmdOutput::Message(message))
+            }
+            Self::Search { query } => handle_search(db, &query),
+            Self::Tokens => handle_tokens(model, db),
+            Self::Completions { shell } => Ok(CmdOutput::Message(completions::script(shell)?)),
+            Self::Config => Err("Run `claippy config` to see the settings in effect".into()),
+            Self::Refresh => {
+                let mut conversation = db.read_current_conversation()?;
+                let refreshed = conversation.refresh_context();
+                db.write_conversation(&conversation)?;
+                Ok(CmdOutput::Message(format!(
+                    "{refreshed} contexts will be resent with the next message"
+                )))
+            }
+            Self::SetSystemPrompt { prompt } => {
+                let mut conversation = db.read_current_conversation()?;
+                let message = match &prompt {
+                    Some(_) => "Set the system prompt for conversation ",
+                    None => "Reset to the default system prompt for conversation ",
+                };
+                conversation.system_prompt = prompt;
+                db.write_conversation(&conversation)?;
+                Ok(CmdOutput::Message(message.to_owned() + &conversation.id))
+            }
+            Self::Retry => {
+                let mut conversation = db.read_current_conversation()?;
+                conversation.pop_assistant_message()?;
+                generate_response(model, conversation, None, db, render)
+            }
+            Self::Undo => {
+                let mut conversation = db.read_current_conversation()?;
+                let message = conversation
+                    .pop_last_exchange()
+                    .ok_or("The conversation has no messages to undo")?;
+                db.write_conversation(&conversation)?;
+                let first_line = message.lines().next().unwrap_or_default();
+                Ok(CmdOutput::Message(format!(
+                    "Removed the last message: {first_line}"
+                )))
+            }
+            Self::Summarize { keep } => handle_summarize(model, db, render, keep),
+            Self::Continue => {
+                let mut conversation = db.read_current_conversation()?;
+                let previous = conversation.pop_assistant_message()?.as_message().content;
+                let previous = [INTERRUPTED_MARKER, INCOMPLETE_MARKER]
+                    .iter()
+                    .fold(previous.as_str(), |previous, marker| {
+                        previous.strip_suffix(marker).unwrap_or(previous)
+                    })
+                    .to_owned();
+                generate_response(model, conversation, Some(previous), db, render)
+            }
+        }
+    }
+}
+
+/// A message as `history` shows it: its role, then its content, followed by a blank line.
+fn format_history_message(render: &RenderOptions, skin: &MadSkin, message: &RichMessage) -> String {
+    let mut formatted = format!("{}\n", Colorize::blue(message.role()).bold());
+    if render.raw {
+        formatted += &message.as_message().content;
+    } else {
+        // The context first sent with a message is listed, rather than shown in full
+        if !message.context().is_empty() {
+            let context = message
+                .context()
+                .iter()
+                .map(WorkspaceContext::to_string)
+                .collect::<Vec<String>>()
+                .join(", ");
+            formatted += &format!("{}\n", format!("[Context: {context}]").dimmed());
+        }
+        formatted += &format_message(render, skin, message.parts_without_context());
+    }
+    formatted.push('\n');
+    formatted
+}
+
+// Prints the conversation, then watches the conversation store and prints each message as it's added. The store
+// isn't locked, so another process can keep adding to the conversation.
+fn handle_watch(
+    db: &Db,
+    conversation_id: Option<String>,
+    render: &RenderOptions,
+) -> Result<CmdOutput> {
+    let id = match conversation_id {
+        Some(id) if !db.conversation_exists(&id) => {
+            // An invalid id is reported as such, rather than as missing
+            Db::check_id(&id)?;
+            return Err(format!("No conversation {id}. Use `conversations` to list them").into());
+        }
+        Some(id) => id,
+        None => db.read_current_conversation()?.id,
+    };
+
+    // Watch before the first read, so that no change is missed
+    let (sender, changes) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(db.path(), RecursiveMode::NonRecursive)?;
+
+    let skin = render.skin();
+    let interrupt = InterruptGuard::new();
+    println!(
+        "{}",
+        format!("Watching conversation {id}. Press Ctrl-C to stop").dimmed()
+    );
+    // How many messages have been printed, and the content of the last, to tell when it's replaced
+    let mut shown = 0;
+    let mut last_shown: Option<String> = None;
+    loop {
+        if !db.conversation_exists(&id) {
+            return Ok(CmdOutput::Message(format!("Conversation {id} was deleted")));
+        }
+        let conversation = db.read_conversation(&id)?;
+        let messages = &conversation.messages;
+        let (rewritten, unshown) = messages_to_show(messages, shown, last_shown.as_deref());
+        if rewritten {
+            println!("{}\n", "[The conversation was rewritten]".dimmed());
+        }
+        for message in unshown {
+            print!("{}", format_history_message(render, &skin, message));
+        }
+        shown = messages.len();
+        last_shown = messages.last().map(|m| m.as_message().content);
+        io::stdout().flush()?;
+
+        // Wait for a change, and then for the writes that make it up to finish
+        loop {
+            if interrupt.interrupted() {
+                return Ok(CmdOutput::Done);
+            }
+            match changes.recv_timeout(Duration::from_millis(200)) {
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) => log::warn!("Error watching {}: {e}", db.path().display()),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err("Stopped receiving changes to the conversation".into())
+                }
+            }
+        }
+        while changes.recv_timeout(Duration::from_millis(50)).is_ok() {}
+    }
+}
+
+/// The messages of a watched conversation that are still to be printed, given how many were printed and the
+/// content of the last of them. If an undo, retry or clear replaced messages that were printed, the conversation
+/// is printed again from the start, which the flag says.
+fn messages_to_show<'a>(
+    messages: &'a [RichMessage],
+    shown: usize,
+    last_shown: Option<&str>,
+) -> (bool, &'a [RichMessage]) {
+    let last_still_shown = shown == 0
+        || messages
+            .get(shown - 1)
+            .map(|m| m.as_message().content)
+            .as_deref()
+            == last_shown;
+    if last_still_shown {
+        (false, &messages[shown..])
+    } else {
+        (true, messages)
+    }
+}
+
+fn handle_query(
+    model: &impl Queryable,
+    query: String,
+    db: &Db,
+    render: &RenderOptions,
+) -> Result<CmdOutput> {
+    let mut conversation = db.read_current_conversation()?;
+    let mentioned = conversation.add_mentioned_files(&query);
+    if !mentioned.added.is_empty() {
+        let added = mentioned
+            .added
+            .iter()
+            .map(WorkspaceContext::to_string)
+            .collect::<Vec<String>>()
+            .join(", ");
+        eprintln!("{}", format!("[Added context: {added}]").dimmed());
+    }
+    // The conversation is only written once a response arrives, so if the query fails it is left as it was
+    let escaped = conversation.add_user_message(query)?;
+    if !escaped.is_empty() {
+        eprintln!(
+            "{}",
+            format!(
+                "[Escaped </ClaippyContext> in: {}, so it can't end the context early]",
+                escaped.join(", ")
+            )
+            .dimmed()
+        );
+    }
+    generate_response(model, conversation, None, db, render)
+}
+
+fn handle_summarize(
+    model: &impl Queryable,
+    db: &Db,
+    render: &RenderOptions,
+    keep: usize,
+) -> Result<CmdOutput> {
+    let mut conversation = db.read_current_conversation()?;
+    let end = conversation
+        .summary_end(keep)
+        .ok_or("There are no earlier messages to summarize")?;
+
+    // The conversations worth summarizing are the long ones, so the messages are windowed as a query's would be
+    let mut messages = conversation.windowed_messages_before(end, &HistoryWindow::from_env());
+    messages.push(Message::user(SUMMARIZE_PROMPT.to_owned()));
+    let query = Messages {
+        messages,
+        system_prompt: conversation.system_prompt.clone(),
+        temperature: None,
+    };
+    let spinner = Spinner::start(render);
+    let mut summary = String::new();
+    let mut usage = TokenUsage::default();
+    for chunk in model.generate(query)? {
+        match chunk? {
+            ResponseChunk::Text(text) => summary.push_str(&text),
+            ResponseChunk::Usage(chunk_usage) => usage += chunk_usage,
+            _ => {}
         }
     }
+    drop(spinner);
+
+    conversation.replace_with_summary(end, summary.trim().to_owned());
+    conversation.add_token_usage(usage);
+    db.write_conversation(&conversation)?;
+    Ok(CmdOutput::Message(format!(
+        "Summarized {end} messages (~{} tokens):\n\n{}",
+        estimate_tokens(&summary),
+        summary.trim()
+    )))
 }
 
-fn handle_query(model: &impl Queryable, query: String, db: &Db) -> Result<CmdOutput> {
-    let skin = MadSkin::default();
+/// Prints the request body that a query would send, without sending it or changing the conversation.
+fn handle_dry_run(model: &impl Queryable, query: String, db: &Db) -> Result<CmdOutput> {
     let mut conversation = db.read_current_conversation()?;
+    conversation.add_mentioned_files(&query);
+    let not_retrieved = conversation.remove_unsent_running_context();
     conversation.add_user_message(query)?;
-    let query_response = model.generate(conversation.as_messages().into())?;
+    let body = model.request_body(conversation.as_query())?;
+    let pretty = serde_json::to_string_pretty(&serde_json::from_str::<serde_json::Value>(&body)?)?;
+    let mut output = format!(
+        "{pretty}\n{}",
+        format!(
+            "[Request body: {} bytes, ~{} tokens]",
+            body.len(),
+            estimate_tokens(&body)
+        )
+        .dimmed()
+    );
+    if let Some(listed) = list_not_retrieved(&not_retrieved) {
+        output += &format!("\n{}", format!("[Left out: {listed}]").dimmed());
+    }
+    Ok(CmdOutput::Message(output))
+}
+
+// The context left out of a preview of the next message because retrieving it would run a command or fetch a URL,
+// described for the preview, if there is any.
+fn list_not_retrieved(contexts: &[WorkspaceContext]) -> Option<String> {
+    if contexts.is_empty() {
+        return None;
+    }
+    let listed = contexts
+        .iter()
+        .map(WorkspaceContext::to_string)
+        .collect::<Vec<String>>()
+        .join(", ");
+    Some(format!(
+        "{listed}, which would be run or fetched. They're still sent with the next message"
+    ))
+}
+
+/// Estimates the tokens the next query would send: the conversation so far, the context that hasn't been sent yet,
+/// and the whole request including the system prompt.
+fn handle_tokens(model: &impl Queryable, db: &Db) -> Result<CmdOutput> {
+    let mut conversation = db.read_current_conversation()?;
+    let history_tokens: usize = conversation
+        .as_messages()
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+
+    let not_retrieved = conversation.remove_unsent_running_context();
+    conversation.add_user_message(String::new())?;
+    let query = conversation.as_query();
+    let context_tokens = query
+        .messages
+        .last()
+        .map_or(0, |message| estimate_tokens(&message.content));
+    let request_tokens = estimate_tokens(&model.request_body(query)?);
 
-    let mut full_content = String::new();
-    let mut current_line = String::new();
+    let mut output = format!(
+        "Messages:       ~{history_tokens:>7} tokens\n\
+         Unsent context: ~{context_tokens:>7} tokens\n\
+         Full request:   ~{request_tokens:>7} tokens\n{}",
+        "(Estimates, at about 4 bytes per token)".dimmed()
+    );
+    if let Some(listed) = list_not_retrieved(&not_retrieved) {
+        output += &format!("\n{}", format!("(Not estimated: {listed})").dimmed());
+    }
+    Ok(CmdOutput::Message(output))
+}
 
-    let mut line_count = 1;
+/// Streams a response to the conversation's messages, then saves the conversation with the response added.
+/// If `continued` is given, it's a response that was removed from the conversation. The model is asked to continue
+/// it, and it's stored again with the continuation appended.
+fn generate_response(
+    model: &impl Queryable,
+    mut conversation: Conversation,
+    continued: Option<String>,
+    db: &Db,
+    render: &RenderOptions,
+) -> Result<CmdOutput> {
+    let mut query = conversation.as_query();
+    if let Some(previous) = &continued {
+        query.messages.push(Message::assistant(previous.clone()));
+        query
+            .messages
+            .push(Message::user(CONTINUE_PROMPT.to_owned()));
+    }
+    // Taken before the request is sent, so that Ctrl-C while waiting for the response to start stops it too
+    let interrupt = InterruptGuard::new();
+    let mut spinner = Spinner::start(render);
+    let query_response = model.generate(query)?;
+
+    let mut full_content = continued.clone().unwrap_or_default();
+    // In JSON mode nothing is streamed, so that stdout holds only the final JSON object.
+    let mut renderer = (!render.json).then(|| StreamRenderer::new(render));
+    if let (Some(renderer), Some(previous)) = (renderer.as_mut(), &continued) {
+        renderer.resume_after(previous);
+    }
+    let mut usage = TokenUsage::default();
+    let mut truncated = false;
+    let mut thinking = String::new();
 
+    let mut failure = None;
     for chunk_result in query_response {
-        let chunk = chunk_result?;
-        for c in chunk.chars() {
-            if c == '\n' {
-                // Process and print the completed line
-                print!("{}", skin.inline(&current_line));
-                println!();
-                line_count += 1;
-                io::stdout().flush()?;
-                full_content.push_str(&current_line);
-                full_content.push('\n');
-                current_line.clear();
-            } else {
-                current_line.push(c);
+        // Erase the spinner as soon as the response starts
+        drop(spinner.take());
+        if interrupt.interrupted() {
+            break;
+        }
+        let chunk = match chunk_result {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        match chunk {
+            ResponseChunk::Text(text) => {
+                if let Some(renderer) = renderer.as_mut() {
+                    renderer.push(&text)?;
+                }
+                full_content.push_str(&text);
             }
+            ResponseChunk::Thinking(text) => {
+                if let Some(renderer) = renderer.as_mut() {
+                    renderer.push_thinking(&text)?;
+                }
+                thinking.push_str(&text);
+            }
+            ResponseChunk::Usage(chunk_usage) => usage += chunk_usage,
+            ResponseChunk::Stop(reason) => truncated = reason == StopReason::MaxTokens,
         }
     }
+    let interrupted = interrupt.interrupted();
+    drop(interrupt);
 
-    // If there's any remaining content in current_line, print it
-    if !current_line.is_empty() {
-        print!("{}", skin.inline(&current_line));
-        println!();
-        line_count += 1;
-        io::stdout().flush()?;
-        full_content.push_str(&current_line);
+    if let Some(renderer) = renderer {
+        renderer.finish()?;
     }
-
-    erase_last_n_lines_simple(line_count);
-    let parsed_message = parse_message_parts(full_content);
-    println!("{}", format_message(&skin, &parsed_message));
+    if let Some(e) = failure.take() {
+        // With nothing received there's nothing to keep, so the conversation is left as it was
+        if full_content == continued.unwrap_or_default() && thinking.is_empty() {
+            return Err(e);
+        }
+        failure = Some(e);
+        full_content.push_str(INCOMPLETE_MARKER);
+    }
+    if interrupted {
+        full_content.push_str(INTERRUPTED_MARKER);
+    }
+    let mut parsed_message = parse_message_parts(full_content.clone());
+    if !thinking.is_empty() {
+        parsed_message.insert(0, MessageParts::Thinking(thinking));
+    }
+    let parts = serde_json::to_value(&parsed_message)?;
 
     conversation.add_assistant_message(parsed_message);
+    conversation.add_token_usage(usage);
     db.write_conversation(&conversation)?;
-    Ok(CmdOutput::Done)
-}
 
-fn erase_last_n_lines_simple(n: usize) {
-    // Move up N lines
-    print!("\x1b[{}A", n);
-    // Clear from cursor down
-    print!("\x1b[J");
-    // Flush stdout
-    std::io::stdout().flush().unwrap();
-}
+    // The partial response is saved first, so it can be continued or retried
+    if let Some(e) = failure {
+        if !render.json {
+            println!(
+                "{}",
+                "[Response incomplete: the partial response was saved. Use `continue` or `retry`]"
+                    .dimmed()
+            );
+        }
+        return Err(e);
+    }
 
-const CLAIPPY_ARTIFACT: &str = "ClaippyArtifact";
+    if render.json {
+        return Ok(CmdOutput::Json(serde_json::json!({
+            "conversation_id": conversation.id,
+            "content": full_content,
+            "parts": parts,
+            "usage": usage,
+            "conversation_usage": conversation.token_usage,
+            "truncated": truncated,
+            "interrupted": interrupted,
+        })));
+    }
 
-fn parse_message_parts(full_content: String) -> Vec<MessageParts> {
-    let mut parts = Vec::new();
-    // Ideally use a real XML parser here instead
-    let artifact_regex = Regex::new(&format!(
-        r"(?ms)<{}\s*([^>]*?)>(.*?)</{}>",
-        CLAIPPY_ARTIFACT, CLAIPPY_ARTIFACT
-    ))
-    .unwrap();
-    let mut last_end = 0;
+    if interrupted {
+        println!(
+            "{}",
+            "[Response interrupted: the partial response was saved]".dimmed()
+        );
+    }
+
+    if truncated {
+        println!(
+            "{}",
+            "[Response truncated: the model reached its output token limit. Use `continue` to extend it]".dimmed()
+        );
+    }
 
-    for cap in artifact_regex.captures_iter(&full_content) {
-        let start = cap.get(0).unwrap().start();
-        let end = cap.get(0).unwrap().end();
+    println!(
+        "{}",
+        format!(
+            "[{} (conversation total: {})]",
+            usage, conversation.token_usage
+        )
+        .dimmed()
+    );
+    Ok(CmdOutput::Done)
+}
+
+// How many characters of a long matching line to show either side of the match.
+const SEARCH_SNIPPET_CHARS: usize = 60;
 
-        log::info!("Capture {start:?} {end:?} ");
+/// Case-insensitive search of the messages of all conversations, most recently modified first. Each match is shown
+/// with the nearest non-blank lines before and after it.
+fn handle_search(db: &Db, query: &str) -> Result<CmdOutput> {
+    let pattern = RegexBuilder::new(&regex::escape(query))
+        .case_insensitive(true)
+        .build()?;
+
+    let mut results = Vec::new();
+    for summary in db.list_conversations()? {
+        let conversation = db.read_conversation(&summary.id)?;
+        let mut matches = Vec::new();
+        for message in conversation.as_messages() {
+            let lines: Vec<&str> = message.content.lines().collect();
+            let shown = search_context_lines(&lines, |line| pattern.is_match(line));
+            if shown.is_empty() {
+                continue;
+            }
 
-        // Add any text before the artifact as Markdown
-        if start > last_end {
-            parts.push(MessageParts::Markdown(
-                full_content[last_end..start].to_string(),
+            let mut snippet = format!("  {}:", message.role.dimmed());
+            let mut previous: Option<usize> = None;
+            for index in shown {
+                if previous.is_some_and(|previous| {
+                    lines[previous + 1..index]
+                        .iter()
+                        .any(|line| !line.trim().is_empty())
+                }) {
+                    snippet += &format!("\n    {}", "...".dimmed());
+                }
+                let line = lines[index];
+                let shown_line = match pattern.find(line) {
+                    Some(found) => search_snippet(line, found.start(), found.end()),
+                    None => search_context_snippet(line).dimmed().to_string(),
+                };
+                snippet += &format!("\n    {shown_line}");
+                previous = Some(index);
+            }
+            matches.push(snippet);
+        }
+        if !matches.is_empty() {
+            results.push(format!(
+                "{}\n{}",
+                Colorize::bold(summary.id.as_str()),
+                matches.join("\n")
             ));
         }
+    }
 
-        // Parse attributes
-        let attrs = cap.get(1).unwrap().as_str();
-        let identifier_regex = Regex::new(r#"identifier="([^"]+)""#).unwrap();
-        let language_regex = Regex::new(r#"language="([^"]+)""#).unwrap();
+    if results.is_empty() {
+        return Ok(CmdOutput::Message(format!("No messages matching {query}")));
+    }
+    Ok(CmdOutput::Message(results.join("\n\n")))
+}
 
-        let identifier = identifier_regex
-            .captures(attrs)
-            .and_then(|c| c.get(1))
-            .map(|m| m.as_str().to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+/// The indices of the lines to show for a search, in order: each matching line, and the nearest non-blank lines
+/// before and after it.
+fn search_context_lines(lines: &[&str], is_match: impl Fn(&str) -> bool) -> BTreeSet<usize> {
+    let is_blank = |index: &usize| lines[*index].trim().is_empty();
+    let mut shown = BTreeSet::new();
+    for (index, line) in lines.iter().enumerate() {
+        if is_match(line) {
+            shown.insert(index);
+            shown.extend((0..index).rev().find(|i| !is_blank(i)));
+            shown.extend((index + 1..lines.len()).find(|i| !is_blank(i)));
+        }
+    }
+    shown
+}
 
-        let language = language_regex
-            .captures(attrs)
-            .and_then(|c| c.get(1))
-            .map(|m| m.as_str().to_string());
+/// The part of a line around a match, with the match highlighted.
+fn search_snippet(line: &str, start: usize, end: usize) -> String {
+    let before = &line[..start];
+    let after = &line[end..];
+    let before = match before.char_indices().rev().nth(SEARCH_SNIPPET_CHARS) {
+        Some((index, _)) => format!("...{}", &before[index..]),
+        None => before.to_owned(),
+    };
+    let after = match after.char_indices().nth(SEARCH_SNIPPET_CHARS) {
+        Some((index, _)) => format!("{}...", &after[..index]),
+        None => after.to_owned(),
+    };
+    format!(
+        "{}{}{}",
+        before.trim_start(),
+        Colorize::yellow(&line[start..end]).bold(),
+        after
+    )
+}
 
-        // Add the artifact
-        parts.push(MessageParts::Artifact {
-            identifier,
-            language,
-            content: cap.get(2).unwrap().as_str().to_string(),
-        });
+/// The start of a line shown around a match, at most as long as a matching line's snippet.
+fn search_context_snippet(line: &str) -> String {
+    let line = line.trim();
+    match line.char_indices().nth(2 * SEARCH_SNIPPET_CHARS) {
+        Some((index, _)) => format!("{}...", &line[..index]),
+        None => line.to_owned(),
+    }
+}
+
+fn handle_export_artifacts(db: &Db, dir: String) -> Result<CmdOutput> {
+    let conversation = db.read_current_conversation()?;
 
-        last_end = end;
+    // Artifacts are iteratively edited under the same identifier, so only write the final version of each.
+    let mut latest: Vec<ArtifactRef> = Vec::new();
+    for artifact in conversation.artifacts() {
+        latest.retain(|existing| existing.identifier != artifact.identifier);
+        latest.push(artifact);
     }
 
-    // Add any remaining text as Markdown
-    if last_end < full_content.len() {
-        parts.push(MessageParts::Markdown(full_content[last_end..].to_string()));
-        log::info!("Emitted final markdown part")
+    let dir = Path::new(&dir);
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::with_capacity(latest.len());
+    for artifact in &latest {
+        // Different identifiers can have the same file name once cleaned, e.g. `a/b` and `a-b`, so number repeats.
+        let name = artifact_file_name(artifact);
+        let mut path = dir.join(&name);
+        for number in 2.. {
+            if !written.contains(&path.display().to_string()) {
+                break;
+            }
+            path = dir.join(match name.split_once('.') {
+                Some((stem, extension)) => format!("{stem}-{number}.{extension}"),
+                None => format!("{name}-{number}"),
+            });
+        }
+        fs::write(&path, artifact_file_content(artifact))?;
+        written.push(path.display().to_string());
     }
 
-    parts
+    Ok(CmdOutput::Message(format!(
+        "Exported {} artifacts:\n{}",
+        written.len(),
+        written.join("\n")
+    )))
 }
 
-fn format_message(skin: &MadSkin, full_message: &[MessageParts]) -> String {
-    let mut formatted = String::new();
+fn handle_copy_artifact(db: &Db, identifier: Option<String>) -> Result<CmdOutput> {
+    let conversation = db.read_current_conversation()?;
+    let artifact = match &identifier {
+        Some(identifier) => find_artifact(&conversation, identifier)?,
+        None => conversation
+            .artifacts()
+            .last()
+            .ok_or("The current conversation has no artifacts")?,
+    };
+    let content = artifact_file_content(&artifact);
 
-    let ps = SyntaxSet::load_defaults_newlines();
-    let ts = ThemeSet::load_defaults();
+    // There is no clipboard on e.g. a headless server, so print the artifact instead.
+    match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(&content)) {
+        Ok(()) => Ok(CmdOutput::Message(format!(
+            "Copied artifact {} to the clipboard",
+            artifact.identifier
+        ))),
+        Err(e) => {
+            eprintln!(
+                "Warning: could not copy to the clipboard ({e}), printing the artifact instead"
+            );
+            Ok(CmdOutput::Message(
+                content.trim_end_matches('\n').to_owned(),
+            ))
+        }
+    }
+}
 
-    let (term_width, _height) = terminal_size();
+/// The latest version of an artifact, or an error listing the artifacts there are.
+fn find_artifact<'a>(conversation: &'a Conversation, identifier: &str) -> Result<ArtifactRef<'a>> {
+    if let Some(artifact) = conversation.latest_artifact(identifier) {
+        return Ok(artifact);
+    }
 
-    for part in full_message {
-        match part {
-            MessageParts::Markdown(text) => {
-                formatted.push_str(&skin.term_text(text).to_string());
-            }
-            MessageParts::Artifact {
-                identifier,
-                language,
-                content,
-            } => {
-                let artifact_intro = format!(
-                    "[Artifact: {} ({})]\n",
-                    identifier,
-                    language.as_deref().unwrap_or("None"));
-                formatted.push_str(&format!("{}", artifact_intro.dim()));
-
-                if let Some(lang) = language {
-                    log::info!("Language: {}", lang);
-
-                    if let Some(syntax) = ps.syntaxes().iter().find(|s| {
-                        s.name.to_lowercase() == *lang || s.file_extensions.contains(lang)
-                    }) {
-                        let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
-                        let mut highlighted = String::new();
-
-                        for line in LinesWithEndings::from(content) {
-                            let ranges: Vec<(Style, &str)> = h.highlight_line(line, &ps).unwrap();
-                            let escaped = as_24_bit_terminal_escaped(&ranges[..], true);
-                            // Sets the line length to the term with, which allows the background formatting to
-                            // extend to this length, which looks nicer.
-                            highlighted.push_str(&format!("\x1b[{}X", term_width));
-                            highlighted.push_str(&escaped);
+    let mut identifiers: Vec<&str> = Vec::new();
+    for artifact in conversation.artifacts() {
+        if !identifiers.contains(&artifact.identifier) {
+            identifiers.push(artifact.identifier);
+        }
+    }
+    Err(if identifiers.is_empty() {
+        format!("No artifact {identifier}: the current conversation has no artifacts")
+    } else {
+        format!(
+            "No artifact {identifier} in the current conversation. Artifacts are:\n{}",
+            identifiers.join("\n")
+        )
+    }
+    .into())
+}
 
-                        }
-                        highlighted.push_str("\x1b[0m"); // clear syntax, not handled by library
+/// A default file name for an artifact, from its identifier and language. Identifiers come from the model, so
+/// path separators are replaced and `.`, `..` and leading dots are dropped, keeping the file in the directory it's
+/// written to.
+fn artifact_file_name(artifact: &ArtifactRef) -> String {
+    let stem = artifact
+        .identifier
+        .split(['/', '\\'])
+        .filter(|part| !matches!(*part, "" | "." | ".."))
+        .collect::<Vec<&str>>()
+        .join("-");
+    let stem = match stem.trim_start_matches('.') {
+        "" => "artifact",
+        stem => stem,
+    };
+    match artifact.language.and_then(extension_for_language) {
+        Some(extension) => format!("{stem}.{extension}"),
+        None => stem.to_owned(),
+    }
+}
 
-                        formatted.push_str(&highlighted);
-                    } else {
-                        log::warn!("No syntax found for language {}", lang);
+// Artifact content usually starts and ends with the newlines surrounding the artifact tags.
+fn artifact_file_content(artifact: &ArtifactRef) -> String {
+    format!("{}\n", artifact.content.trim_matches('\n'))
+}
 
-                        // Fallback to regular formatting if syntax is not found
-                        formatted.push_str(&content);
-                    }
-                } else {
-                    // No language specified, use regular formatting
-                    formatted.push_str(&content);
-                }
+fn handle_list_workspace_contexts(db: &Db) -> Result<CmdOutput> {
+    let conversation = db.read_current_conversation()?;
+    let mut context_display = format!(
+        "Current context ({} will be sent with the next message):\n",
+        Colorize::yellow("unseen")
+    );
 
-                formatted.push_str("\n");
+    let mut total_bytes = 0;
+    for (index, (context, seen)) in conversation.contexts().into_iter().enumerate() {
+        let size = match context.size() {
+            Some(bytes) => {
+                total_bytes += bytes;
+                format!(
+                    "{bytes:>9} bytes  ~{:>7} tokens",
+                    estimate_tokens_from_bytes(bytes)
+                )
             }
+            None => format!("{:>9} bytes  ~{:>7} tokens", "?", "?"),
+        };
+        let line = format!(
+            "{index:>3}  {:<6}  {size}  {context}",
+            if seen { "seen" } else { "unseen" }
+        );
+        if seen {
+            context_display.push_str(&line);
+        } else {
+            context_display.push_str(&line.yellow().to_string());
         }
+        context_display.push('\n');
     }
 
-    formatted
+    context_display.push_str(&format!(
+        "Total: {total_bytes} bytes, ~{} tokens",
+        estimate_tokens_from_bytes(total_bytes)
+    ));
+    Ok(CmdOutput::Message(context_display))
+}
+
+/// The context to add that is produced when it's sent, rather than read from a file or URL.
+#[derive(Default)]
+struct GeneratedContexts {
+    commands: Vec<String>,
+    diff: bool,
+    staged: bool,
 }
 
-fn handle_add_workspace_contexts(db: &Db, paths: Vec<String>) -> Result<CmdOutput> {
+fn handle_add_workspace_contexts(
+    db: &Db,
+    paths: Vec<String>,
+    tree: bool,
+    refresh: bool,
+    generated: GeneratedContexts,
+) -> Result<String> {
     let mut conversation = db.read_current_conversation()?;
-    let context_display = "Added context:\n".to_owned() + &paths.join("\n");
-    conversation.add_workspace_contexts(paths)?;
+    let refetched = if refresh {
+        conversation.refetch_url_contexts(&paths)?
+    } else {
+        Vec::new()
+    };
+    let mut added = if tree {
+        conversation.add_tree_contexts(paths)?
+    } else {
+        conversation.add_workspace_contexts(paths)?
+    };
+    added
+        .added
+        .extend(conversation.add_command_contexts(generated.commands).added);
+    if generated.diff {
+        added
+            .added
+            .extend(conversation.add_git_diff_context(false).added);
+    }
+    if generated.staged {
+        added
+            .added
+            .extend(conversation.add_git_diff_context(true).added);
+    }
+    // URLs being fetched again are reported as such, rather than as already present
+    added
+        .already_present
+        .retain(|c| !matches!(c, WorkspaceContext::Url(url) if refetched.contains(url)));
     db.write_conversation(&conversation)?;
-    Ok(CmdOutput::Message(context_display))
+
+    let list = |contexts: &[WorkspaceContext]| {
+        contexts
+            .iter()
+            .map(|c| format!("  {c}"))
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+    let mut sections = Vec::new();
+    if !added.added.is_empty() {
+        sections.push(format!("Added context:\n{}", list(&added.added)));
+    }
+    if !refetched.is_empty() {
+        sections.push(format!(
+            "Will fetch again:\n{}",
+            refetched
+                .iter()
+                .map(|url| format!("  {url}"))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+    if !added.already_present.is_empty() {
+        sections.push(
+            format!("Already in context:\n{}", list(&added.already_present))
+                .dimmed()
+                .to_string(),
+        );
+    }
+    if sections.is_empty() {
+        sections.push("No context added".to_owned());
+    }
+    Ok(sections.join("\n"))
+}
+
+fn handle_remove_workspace_contexts(db: &Db, selectors: &[String], all: bool) -> Result<CmdOutput> {
+    let mut conversation = db.read_current_conversation()?;
+    let message = if all {
+        format!(
+            "Removed all context ({})",
+            conversation.remove_all_contexts()
+        )
+    } else {
+        let removed = conversation.remove_workspace_contexts(selectors)?;
+        "Removed context:\n".to_owned()
+            + &removed
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+    };
+    db.write_conversation(&conversation)?;
+    Ok(CmdOutput::Message(message))
+}
+
+/// Replaces the last exchange with one for an edited version of the last user message.
+fn handle_edit(model: &impl Queryable, db: &Db, render: &RenderOptions) -> Result<()> {
+    let mut conversation = db.read_current_conversation()?;
+    let query = conversation
+        .pop_last_exchange()
+        .ok_or("No previous message to edit")?;
+
+    let edited = edit_in_editor(&query, &db.path().join(".claippy-edit.md"))?;
+    // Abandoning the edit leaves the conversation as it was.
+    if edited.trim().is_empty() {
+        println!("Empty message, nothing resubmitted");
+        return Ok(());
+    }
+
+    db.write_conversation(&conversation)?;
+    if let CmdOutput::Json(value) = handle_query(model, edited.trim_end().to_owned(), db, render)? {
+        println!("{}", value);
+    }
+    Ok(())
 }
 
-fn handle_repl(model: &impl Queryable, db: &Db) -> Result<CmdOutput> {
+/// Parses the argument to `!temp`: a temperature, or `reset` to go back to the configured temperature.
+fn parse_session_temperature(value: &str) -> Result<Option<f32>> {
+    match value {
+        "" => Err(
+            "!temp requires a temperature between 0 and 2 (0 and 1 for Bedrock), or reset".into(),
+        ),
+        "reset" => Ok(None),
+        value => {
+            let temperature = value
+                .parse()
+                .map_err(|_| format!("Invalid temperature {value}"))?;
+            Ok(Some(clamp_sampling_parameter(
+                "temperature",
+                temperature,
+                MAX_TEMPERATURE,
+            )))
+        }
+    }
+}
+
+fn handle_repl(model: &impl Queryable, db: &Db, render: &RenderOptions) -> Result<CmdOutput> {
+    // `!temp` sets the temperature for the rest of the session. The model is used as a trait object, so that
+    // running `!repl` within the REPL doesn't wrap its type again.
+    let mut model = WithTemperature::<dyn Queryable> {
+        model,
+        temperature: None,
+    };
     let prompt = format!("{}", Colorize::bold("claippy> ").cyan());
-    let mut rl = make_readline(&prompt)?;
+    // Ctrl-F adds the files picked in skim to the context. It runs while the REPL waits for input, so it opens
+    // and locks the store itself.
+    let add_context = |paths: Vec<String>| -> Result<String> {
+        let db = Db::create()?;
+        let _lock = db.lock()?;
+        handle_add_workspace_contexts(&db, paths, false, false, GeneratedContexts::default())
+    };
+    let mut rl = make_readline(&prompt, Box::new(add_context))?;
 
     let repl_history_path = db.path().join(".claippy-repl-history");
 
@@ -316,17 +1669,61 @@ fn handle_repl(model: &impl Queryable, db: &Db) -> Result<CmdOutput> {
             Ok(line) if line.trim().is_empty() => continue,
             Ok(line) => {
                 rl.add_history_entry(line.as_str())?;
-                let input = line.trim_start();
+                let line = join_continued_lines(&line);
+                let input = line.trim();
 
-                if let Some(cmd_str) = input.strip_prefix('!') {
-                    let cmd = CliCmd::parse_args(cmd_str.split_whitespace().map(String::from))?;
-                    match cmd.execute(model, db)? {
-                        CmdOutput::Done => continue,
-                        CmdOutput::Message(msg) => println!("{}", msg),
+                if let Some(value) = input
+                    .strip_prefix("!temp")
+                    .filter(|v| v.is_empty() || v.starts_with(' '))
+                {
+                    match parse_session_temperature(value.trim()) {
+                        Ok(temperature) => {
+                            model.temperature = temperature;
+                            match temperature {
+                                Some(temperature) => {
+                                    println!("Temperature set to {temperature} for this session")
+                                }
+                                None => println!("Temperature reset to the configured default"),
+                            }
+                        }
+                        Err(e) => println!("Error: {e}"),
+                    }
+                } else if input == "!edit" {
+                    if let Err(e) = db.lock().and_then(|_lock| handle_edit(&model, db, render)) {
+                        println!("Edit Error: {:?}", e);
+                    }
+                } else if let Some(cmd_str) = input.strip_prefix('!') {
+                    // Arguments can be quoted as in a shell, e.g. `!add --cmd 'cargo test'`
+                    let args = shlex::split(cmd_str)
+                        .unwrap_or_else(|| cmd_str.split_whitespace().map(String::from).collect());
+                    let cmd = match CliCmd::parse_args(args.into_iter()) {
+                        // The settings are resolved when claippy starts, so they can only be printed from there
+                        Ok(CliCmd::Config) => {
+                            println!("Run `claippy config` outside the REPL to see the settings in effect");
+                            continue;
+                        }
+                        Ok(cmd) => cmd,
+                        Err(e) => {
+                            println!("{e}");
+                            continue;
+                        }
+                    };
+                    // A failed command, e.g. `!rm` of a context that isn't there, leaves the session running
+                    match cmd.execute(&model, db, render) {
+                        Ok(CmdOutput::Done) => (),
+                        Ok(CmdOutput::Message(msg)) => println!("{}", msg),
+                        Ok(CmdOutput::Json(value)) => println!("{}", value),
+                        Err(e) => println!("Error: {e}"),
                     }
                 } else {
-                    if let Err(e) = handle_query(model, input.to_string(), db) {
-                        println!("Query Error: {:?}", e);
+                    let query = input.to_string();
+                    match db
+                        .lock()
+                        .and_then(|_lock| handle_query(&model, query, db, render))
+                    {
+                        Ok(CmdOutput::Json(value)) => println!("{}", value),
+                        Ok(_) => (),
+                        Err(e) => println!("Query Error: {:?}", e),
                     }
                 }
             }
@@ -343,3 +1740,263 @@ fn handle_repl(model: &impl Queryable, db: &Db) -> Result<CmdOutput> {
 
     Ok(CmdOutput::Done)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::MockQueryable;
+    use crate::render::ArtifactLayout;
+    use std::thread;
+
+    fn render() -> RenderOptions {
+        RenderOptions {
+            color: false,
+            interactive: false,
+            json: false,
+            raw: false,
+            theme: None,
+            thinking: false,
+            artifacts: ArtifactLayout {
+                headers: true,
+                line_numbers: false,
+            },
+            width: 80,
+        }
+    }
+
+    fn last_message(db: &Db) -> String {
+        let conversation = db.read_current_conversation().unwrap();
+        conversation.messages.last().unwrap().as_message().content
+    }
+
+    #[test]
+    fn query_saves_streamed_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        let model = MockQueryable::new().respond_with(vec![
+            Ok(ResponseChunk::Text("Hel".to_owned())),
+            Ok(ResponseChunk::Text("lo".to_owned())),
+            Ok(ResponseChunk::Usage(TokenUsage {
+                input_tokens: 3,
+                output_tokens: 2,
+            })),
+        ]);
+
+        handle_query(&model, "Hi".to_owned(), &db, &render()).unwrap();
+
+        let queries = model.take_queries();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].messages.last().unwrap().content.ends_with("Hi"));
+        let conversation = db.read_current_conversation().unwrap();
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(last_message(&db), "Hello");
+        assert_eq!(conversation.token_usage.output_tokens, 2);
+    }
+
+    #[test]
+    fn query_saves_partial_response_when_stream_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        let model = MockQueryable::new().respond_with(vec![
+            Ok(ResponseChunk::Text("Partial".to_owned())),
+            Err("connection reset".to_owned()),
+        ]);
+
+        let result = handle_query(&model, "Hi".to_owned(), &db, &render());
+
+        assert_eq!(result.err().unwrap().to_string(), "connection reset");
+        assert_eq!(last_message(&db), format!("Partial{INCOMPLETE_MARKER}"));
+    }
+
+    #[test]
+    fn query_failing_before_any_text_leaves_conversation_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        let model = MockQueryable::new().respond_with(vec![Err("throttled".to_owned())]);
+
+        assert!(handle_query(&model, "Hi".to_owned(), &db, &render()).is_err());
+        assert!(db.read_current_conversation().unwrap().messages.is_empty());
+    }
+
+    #[test]
+    fn search_shows_nearest_non_blank_lines_around_matches() {
+        let lines = [
+            "intro",
+            "",
+            "before",
+            "",
+            "match one",
+            "between",
+            "match two",
+            "",
+            "",
+            "after",
+            "unrelated",
+            "end",
+        ];
+        let shown = search_context_lines(&lines, |line| line.starts_with("match"));
+        assert_eq!(shown.into_iter().collect::<Vec<_>>(), [2, 4, 5, 6, 9]);
+    }
+
+    fn artifact<'a>(identifier: &'a str, language: Option<&'a str>) -> ArtifactRef<'a> {
+        ArtifactRef {
+            identifier,
+            language,
+            content: "",
+        }
+    }
+
+    #[test]
+    fn artifact_file_names_stay_in_their_directory() {
+        for (identifier, language, name) in [
+            ("hello", Some("python"), "hello.py"),
+            ("notes", None, "notes"),
+            ("../../.bashrc", None, "bashrc"),
+            ("/etc/passwd", None, "etc-passwd"),
+            ("a/b\\c", Some("rust"), "a-b-c.rs"),
+            ("..", None, "artifact"),
+            ("", Some("go"), "artifact.go"),
+        ] {
+            assert_eq!(
+                artifact_file_name(&artifact(identifier, language)),
+                name,
+                "{identifier}"
+            );
+        }
+    }
+
+    #[test]
+    fn export_artifacts_writes_hostile_identifiers_inside_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        let mut conversation = db.read_current_conversation().unwrap();
+        let parts = ["../escaped", "a/b", "a-b", "/tmp/absolute"]
+            .into_iter()
+            .map(|identifier| MessageParts::Artifact {
+                identifier: identifier.to_owned(),
+                language: Some("python".to_owned()),
+                content: format!("# {identifier}"),
+            })
+            .collect();
+        conversation.add_assistant_message(parts);
+        db.write_conversation(&conversation).unwrap();
+
+        let export_dir = dir.path().join("export");
+        handle_export_artifacts(&db, export_dir.display().to_string()).unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&export_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["a-b-2.py", "a-b.py", "escaped.py", "tmp-absolute.py"]
+        );
+        assert!(!dir.path().join("escaped.py").exists());
+        assert_eq!(
+            fs::read_to_string(export_dir.join("a-b-2.py")).unwrap(),
+            "# a-b\n"
+        );
+    }
+
+    fn add_exchange(conversation: &mut Conversation, query: &str, response: &str) {
+        conversation.add_user_message(query.to_owned()).unwrap();
+        conversation.add_assistant_message(vec![MessageParts::Markdown(response.to_owned())]);
+    }
+
+    #[test]
+    fn summarize_counts_the_summary_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        let mut conversation = db.read_current_conversation().unwrap();
+        for n in 1..=3 {
+            add_exchange(&mut conversation, &format!("Q{n}"), &format!("A{n}"));
+        }
+        db.write_conversation(&conversation).unwrap();
+        let model = MockQueryable::new().respond_with(vec![
+            Ok(ResponseChunk::Text("Summary".to_owned())),
+            Ok(ResponseChunk::Usage(TokenUsage {
+                input_tokens: 10,
+                output_tokens: 4,
+            })),
+        ]);
+
+        handle_summarize(&model, &db, &render(), 1).unwrap();
+
+        let query = &model.take_queries()[0];
+        let contents: Vec<&str> = query.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Q1", "A1", "Q2", "A2", SUMMARIZE_PROMPT]);
+        let conversation = db.read_current_conversation().unwrap();
+        assert_eq!(conversation.token_usage.input_tokens, 10);
+        assert_eq!(conversation.token_usage.output_tokens, 4);
+        assert_eq!(conversation.messages.len(), 4);
+    }
+
+    #[test]
+    fn summarized_messages_are_windowed() {
+        let mut conversation = Conversation::empty("test");
+        for n in 1..=3 {
+            add_exchange(&mut conversation, &format!("Q{n}"), &format!("A{n}"));
+        }
+        let window = HistoryWindow {
+            max_messages: Some(2),
+            max_tokens: None,
+        };
+        let messages = conversation.windowed_messages_before(4, &window);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].content.ends_with("Q2"));
+        assert!(messages[0].content.contains("2 earlier messages"));
+        assert_eq!(messages[1].content, "A2");
+    }
+
+    #[test]
+    fn watch_prints_new_messages_and_starts_again_when_rewritten() {
+        let mut conversation = Conversation::empty("test");
+        add_exchange(&mut conversation, "First", "One");
+        let messages = &conversation.messages;
+        let content = |index: usize| messages[index].as_message().content;
+        // Whether it starts again, and how many messages from the end are printed
+        let show = |messages, shown, last_shown: Option<&str>| {
+            let (rewritten, unshown) = messages_to_show(messages, shown, last_shown);
+            (rewritten, unshown.len())
+        };
+
+        assert_eq!(show(messages, 0, None), (false, 2));
+        assert_eq!(show(messages, 1, Some(&content(0))), (false, 1));
+        assert_eq!(show(messages, 2, Some(&content(1))), (false, 0));
+        // A retry replaced the last message, and an undo removed it
+        assert_eq!(show(messages, 2, Some("Another")), (true, 2));
+        assert_eq!(show(&messages[..1], 2, Some(&content(1))), (true, 1));
+    }
+
+    #[test]
+    fn watch_stops_when_the_conversation_is_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_owned()).unwrap();
+        db.create_conversation("watched").unwrap();
+        let (done, finished) = mpsc::channel();
+        let path = dir.path().to_owned();
+        thread::spawn(move || {
+            let db = Db::open(path).unwrap();
+            let output = handle_watch(&db, Some("watched".to_owned()), &render());
+            done.send(output.map_err(|e| e.to_string())).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(300));
+        let mut conversation = db.read_conversation("watched").unwrap();
+        add_exchange(&mut conversation, "Hi", "Hello");
+        db.write_conversation(&conversation).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(finished.try_recv().is_err(), "watch stopped early");
+        db.delete_conversation("watched").unwrap();
+
+        match finished.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Ok(CmdOutput::Message(message)) => {
+                assert_eq!(message, "Conversation watched was deleted")
+            }
+            Ok(_) => panic!("unexpected output"),
+            Err(e) => panic!("watch failed: {e}"),
+        }
+    }
+}
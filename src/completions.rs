@@ -0,0 +1,304 @@
+use std::fmt::Write;
+
+use clap::{Command as ClapCommand, ValueHint};
+
+use crate::{command::cli, model::Result};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(name: &str) -> Option<Shell> {
+        match name {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// What a command's positional arguments are completed with.
+enum Args {
+    None,
+    Files,
+    Words(Vec<String>),
+}
+
+struct FlagSpec {
+    /// The flag, including its leading `--`.
+    long: String,
+    about: String,
+    takes_value: bool,
+    /// Whether its value is a path.
+    files: bool,
+}
+
+struct CommandSpec {
+    /// The command's name, then its aliases.
+    names: Vec<String>,
+    about: String,
+    flags: Vec<FlagSpec>,
+    args: Args,
+}
+
+/// The completion script for `shell`, to be sourced by it. The commands and flags are taken from the command line
+/// parser, so the completions can't drift from what's accepted.
+pub fn script(shell: Shell) -> Result<String> {
+    let cli = cli();
+    let global_flags = flags(&cli, true);
+    let commands: Vec<CommandSpec> = cli
+        .get_subcommands()
+        .map(|command| CommandSpec {
+            names: std::iter::once(command.get_name().to_owned())
+                .chain(command.get_visible_aliases().map(str::to_owned))
+                .collect(),
+            about: command.get_about().unwrap_or_default().to_owned(),
+            flags: flags(command, false),
+            args: positional_args(command),
+        })
+        .collect();
+
+    match shell {
+        Shell::Bash => bash(&commands, &global_flags),
+        Shell::Zsh => zsh(&commands, &global_flags),
+        Shell::Fish => fish(&commands, &global_flags),
+    }
+}
+
+fn has_file_hint(hint: ValueHint) -> bool {
+    matches!(
+        hint,
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+    )
+}
+
+// The command's `--` flags: either only the global ones, or only its own.
+fn flags(command: &ClapCommand, global: bool) -> Vec<FlagSpec> {
+    command
+        .get_arguments()
+        .filter(|arg| arg.is_global_set() == global)
+        .filter_map(|arg| {
+            Some(FlagSpec {
+                long: format!("--{}", arg.get_long()?),
+                about: arg.get_help().unwrap_or_default().to_owned(),
+                takes_value: arg.is_takes_value_set(),
+                files: has_file_hint(arg.get_value_hint()),
+            })
+        })
+        .collect()
+}
+
+// Paths if any of the command's positional arguments is one, else the possible values of its first.
+fn positional_args(command: &ClapCommand) -> Args {
+    if command
+        .get_positionals()
+        .any(|arg| has_file_hint(arg.get_value_hint()))
+    {
+        return Args::Files;
+    }
+    let Some(arg) = command.get_positionals().next() else {
+        return Args::None;
+    };
+    match arg.get_value_parser().possible_values() {
+        Some(values) => Args::Words(values.map(|value| value.get_name().to_owned()).collect()),
+        None => Args::None,
+    }
+}
+
+fn all_names(commands: &[CommandSpec]) -> String {
+    commands
+        .iter()
+        .flat_map(|command| command.names.iter().map(String::as_str))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn flag_names<'a>(flags: impl Iterator<Item = &'a FlagSpec>) -> Vec<&'a str> {
+    flags.map(|flag| flag.long.as_str()).collect()
+}
+
+fn bash(commands: &[CommandSpec], global_flags: &[FlagSpec]) -> Result<String> {
+    let value_flags = flag_names(global_flags.iter().filter(|flag| flag.takes_value)).join("|");
+    let file_flags = flag_names(global_flags.iter().filter(|flag| flag.files)).join("|");
+    let global_flags = flag_names(global_flags.iter()).join(" ");
+
+    let mut cases = String::new();
+    for command in commands {
+        let mut words = flag_names(command.flags.iter()).join(" ");
+        if let Args::Words(values) = &command.args {
+            words = format!("{words} {}", values.join(" ")).trim().to_owned();
+        }
+        let mut reply = String::new();
+        if !words.is_empty() {
+            reply += &format!(r#"$(compgen -W "{words}" -- "$cur") "#);
+        }
+        if let Args::Files = command.args {
+            reply += r#"$(compgen -f -- "$cur")"#;
+        }
+        if !reply.is_empty() {
+            writeln!(
+                cases,
+                "        {}) COMPREPLY=({}) ;;",
+                command.names.join("|"),
+                reply.trim_end()
+            )?;
+        }
+    }
+
+    Ok(format!(
+        r#"_claippy() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}}
+    local prev=${{COMP_WORDS[COMP_CWORD-1]}}
+    case $prev in
+        {file_flags}) COMPREPLY=($(compgen -f -- "$cur")); return ;;
+    esac
+
+    local i cmd=""
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case ${{COMP_WORDS[i]}} in
+            {value_flags}) ((i++)) ;;
+            --*) ;;
+            *) cmd=${{COMP_WORDS[i]}}; break ;;
+        esac
+    done
+
+    case $cmd in
+        "")
+            if [[ $cur == --* ]]; then
+                COMPREPLY=($(compgen -W "{global_flags}" -- "$cur"))
+            else
+                COMPREPLY=($(compgen -W "{commands}" -- "$cur"))
+            fi
+            ;;
+{cases}    esac
+}}
+complete -o filenames -F _claippy claippy
+"#,
+        commands = all_names(commands),
+    ))
+}
+
+// Escapes a description for a zsh `_arguments` spec or `_describe` entry.
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace(':', "\\:")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+}
+
+fn zsh_flag(flag: &FlagSpec) -> String {
+    let value = match (flag.takes_value, flag.files) {
+        (true, true) => ":file:_files",
+        (true, false) => ":value:",
+        (false, _) => "",
+    };
+    format!("'{}[{}]{value}'", flag.long, zsh_escape(&flag.about))
+}
+
+fn zsh(commands: &[CommandSpec], global_flags: &[FlagSpec]) -> Result<String> {
+    let mut names = String::new();
+    for command in commands {
+        for name in &command.names {
+            writeln!(names, "        '{name}:{}'", zsh_escape(&command.about))?;
+        }
+    }
+
+    let mut flags = String::new();
+    for flag in global_flags {
+        write!(flags, " \\\n        {}", zsh_flag(flag))?;
+    }
+
+    let mut cases = String::new();
+    for command in commands {
+        let mut specs: Vec<String> = command.flags.iter().map(zsh_flag).collect();
+        match &command.args {
+            Args::None => {}
+            Args::Files => specs.push("'*:file:_files'".to_owned()),
+            Args::Words(words) => specs.push(format!("'1:value:({})'", words.join(" "))),
+        }
+        if !specs.is_empty() {
+            writeln!(
+                cases,
+                "                {}) _arguments {} ;;",
+                command.names.join("|"),
+                specs.join(" ")
+            )?;
+        }
+    }
+
+    Ok(format!(
+        r#"#compdef claippy
+
+_claippy() {{
+    local -a commands
+    commands=(
+{names}    )
+
+    local state
+    _arguments -C{flags} \
+        '1: :->command' \
+        '*:: :->args'
+
+    case $state in
+        command) _describe 'command' commands ;;
+        args)
+            case $words[1] in
+{cases}            esac
+            ;;
+    esac
+}}
+
+compdef _claippy claippy
+"#
+    ))
+}
+
+fn fish_flag(condition: &str, flag: &FlagSpec) -> String {
+    let value = match (flag.takes_value, flag.files) {
+        (true, true) => " -r -F",
+        (true, false) => " -r",
+        (false, _) => "",
+    };
+    format!(
+        "complete -c claippy -n {condition} -l {}{value} -d '{}'",
+        flag.long.trim_start_matches("--"),
+        flag.about.replace('\'', "\\'")
+    )
+}
+
+fn fish(commands: &[CommandSpec], global_flags: &[FlagSpec]) -> Result<String> {
+    let mut script = String::from("complete -c claippy -f\n");
+    for flag in global_flags {
+        writeln!(script, "{}", fish_flag("__fish_use_subcommand", flag))?;
+    }
+
+    for command in commands {
+        let about = command.about.replace('\'', "\\'");
+        for name in &command.names {
+            writeln!(
+                script,
+                "complete -c claippy -n __fish_use_subcommand -a {name} -d '{about}'"
+            )?;
+        }
+
+        let seen = format!("'__fish_seen_subcommand_from {}'", command.names.join(" "));
+        for flag in &command.flags {
+            writeln!(script, "{}", fish_flag(&seen, flag))?;
+        }
+        match &command.args {
+            Args::None => {}
+            Args::Files => writeln!(script, "complete -c claippy -n {seen} -F")?,
+            Args::Words(words) => writeln!(
+                script,
+                "complete -c claippy -n {seen} -a '{}'",
+                words.join(" ")
+            )?,
+        }
+    }
+    Ok(script)
+}
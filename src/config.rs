@@ -0,0 +1,121 @@
+use std::{fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::model::Result;
+
+// Written on first run, so the available settings are discoverable. Everything is commented out, so the
+// defaults apply until a setting is uncommented.
+const DEFAULT_CONFIG: &str = r#"# claippy configuration.
+# Environment variables override these settings, and command line flags override environment variables.
+
+# The model backend: bedrock, openai or ollama (CLAIPPY_BACKEND)
+# backend = "bedrock"
+
+# The model to query (--model, CLAIPPY_MODEL_ID)
+# model_id = "anthropic.claude-3-5-sonnet-20241022-v2:0"
+
+# Sampling parameters (--temperature, --top-p). The temperature is between 0 and 2, or 0 and 1 for Bedrock, and
+# top_p between 0 and 1
+# temperature = 0.1
+# top_p = 0.9
+
+# Sequences that end a response when the model generates them (--stop)
+# stop_sequences = ["</answer>"]
+
+# A file to read the system prompt from, instead of the built-in prompt (--system-prompt-file,
+# CLAIPPY_SYSTEM_PROMPT_FILE)
+# system_prompt_file = "/path/to/system-prompt.md"
+
+# The maximum number of tokens in a Bedrock response
+# max_tokens = 4096
+
+# The syntax highlighting theme: a syntect theme name or a path to a .tmTheme file (CLAIPPY_THEME)
+# theme = "base16-ocean.dark"
+
+# Show the reasoning of models that think before responding, dimmed (CLAIPPY_SHOW_THINKING)
+# show_thinking = false
+
+# Introduce each artifact with a line giving its identifier and language (--no-artifact-headers)
+# artifact_headers = true
+
+# Number the lines of artifacts (--line-numbers)
+# line_numbers = false
+
+# Bedrock settings (CLAIPPY_REGION, CLAIPPY_AWS_PROFILE, CLAIPPY_MAX_RETRIES, CLAIPPY_TIMEOUT_SECS,
+# CLAIPPY_THINKING_BUDGET)
+# The AWS region. If unset, AWS_REGION, AWS_DEFAULT_REGION or the profile's region is used, else us-west-2
+# region = "us-west-2"
+# The AWS profile to use. If unset, the default credential provider chain is used
+# aws_profile = "dev"
+# max_retries = 3
+# Seconds to wait for a response to start, and then for each part of it, and for a context command (add --cmd) to
+# finish. 0 waits indefinitely
+# timeout_secs = 120
+# Tokens the model may spend thinking before it responds, from 1024 to less than max_tokens. Thinking models then
+# use a temperature of 1. 0 turns thinking off
+# thinking_budget = 0
+
+# OpenAI-compatible API settings (OPENAI_BASE_URL)
+# openai_base_url = "https://api.openai.com/v1"
+
+# Ollama settings (OLLAMA_HOST)
+# ollama_host = "http://localhost:11434"
+"#;
+
+/// Settings from the config file at `~/.config/claippy/config.toml` (or the platform's equivalent).
+/// Each setting is optional. The precedence of each setting, from highest to lowest, is:
+/// 1. a command line flag, for the settings that have one
+/// 2. its environment variable
+/// 3. this file
+/// 4. the built-in default
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub backend: Option<String>,
+    pub model_id: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub system_prompt_file: Option<String>,
+    pub theme: Option<String>,
+    pub show_thinking: Option<bool>,
+    pub artifact_headers: Option<bool>,
+    pub line_numbers: Option<bool>,
+    pub region: Option<String>,
+    pub aws_profile: Option<String>,
+    pub max_retries: Option<u32>,
+    pub timeout_secs: Option<u64>,
+    pub thinking_budget: Option<u32>,
+    pub openai_base_url: Option<String>,
+    pub ollama_host: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("claippy").join("config.toml"))
+    }
+
+    /// Loads the config file, first creating it with every setting commented out if it doesn't exist.
+    pub fn load() -> Result<Config> {
+        let Some(path) = Self::path() else {
+            log::warn!("No config directory found, using the default config");
+            return Ok(Config::default());
+        };
+        if !path.exists() {
+            if let Err(e) = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, DEFAULT_CONFIG))
+            {
+                log::warn!("Could not create config file {}: {e}", path.display());
+                return Ok(Config::default());
+            }
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()).into())
+    }
+}
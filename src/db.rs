@@ -1,74 +1,291 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    path::PathBuf,
+    time::SystemTime,
+};
 
 use crate::model::{Conversation, Result};
 
+#[cfg(not(feature = "sqlite"))]
+mod file;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(not(feature = "sqlite"))]
+use file::FileStore as DefaultStore;
+#[cfg(feature = "sqlite")]
+use sqlite::SqliteStore as DefaultStore;
+
+/// The `.claippy` directory at the root of the enclosing git repository, created if it doesn't exist.
+pub fn claippy_dir() -> Result<PathBuf> {
+    let mut path: PathBuf = std::env::current_dir()?;
+    loop {
+        if path.join(".git").is_dir() {
+            path = path.join(".claippy");
+            if !path.is_dir() {
+                fs::create_dir_all(&path)?;
+            }
+            return Ok(path);
+        }
+        if !path.pop() {
+            return Err("No .git directory found in any parent directory".into());
+        }
+    }
+}
+
+/// Summary information about a stored conversation, as shown by the conversation listing.
+pub struct ConversationSummary {
+    pub id: String,
+    pub message_count: usize,
+    pub modified: SystemTime,
+}
+
+/// The storage operations `Db` is built on. Conversations are stored by id, alongside which one is current.
+trait Store {
+    fn read(&self, conversation_id: &str) -> Result<Option<Conversation>>;
+    fn write(&self, conversation: &Conversation) -> Result<()>;
+    fn exists(&self, conversation_id: &str) -> bool;
+    fn remove(&self, conversation_id: &str) -> Result<()>;
+    /// All stored conversations, most recently modified first.
+    fn list(&self) -> Result<Vec<ConversationSummary>>;
+    /// The id the current conversation points at, which may no longer exist.
+    fn current(&self) -> Option<String>;
+    fn set_current(&self, conversation_id: Option<&str>) -> Result<()>;
+}
+
+/// An advisory lock on the conversation store, released when dropped.
+pub struct DbLock {
+    _file: File,
+}
+
 /// Stores and retrieves conversations by conversation ID.
-/// Right now this uses/overwrites files, but it could use e.g. sqlite internally
+/// Conversations are stored as one file each, or in a sqlite database when built with the `sqlite` feature.
 pub struct Db {
     path: PathBuf,
+    store: DefaultStore,
 }
 
 impl Db {
     const CURRENT_PATH: &'static str = "current";
+    const LOCK_PATH: &'static str = ".lock";
 
+    /// Opens the store in the enclosing repository's `.claippy` directory.
     pub fn create() -> Result<Db> {
-        let mut path: PathBuf = std::env::current_dir()?;
-        loop {
-            if path.join(".git").is_dir() {
-                path = path.join(".claippy");
-                if !path.is_dir() {
-                    fs::create_dir_all(&path)?;
-                }
-                return Ok(Db { path });
-            }
-            if !path.pop() {
-                return Err("No .git directory found in any parent directory".into());
-            }
-        }
+        Self::open(claippy_dir()?)
+    }
+
+    /// Opens the store in `path`, which must exist.
+    pub fn open(path: PathBuf) -> Result<Db> {
+        let store = DefaultStore::open(&path)?;
+        Ok(Db { path, store })
     }
 
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
 
+    /// Locks the store against other claippy processes, waiting for any process that holds the lock.
+    pub fn lock(&self) -> Result<DbLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.join(Self::LOCK_PATH))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                eprintln!("Waiting for another claippy process to finish with the conversation...");
+                file.lock()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        Ok(DbLock { _file: file })
+    }
+
     pub fn write_conversation(&self, conversation: &Conversation) -> Result<()> {
-        let file_path = self.path.join(&conversation.id);
-        fs::write(file_path, serde_json::to_string_pretty(conversation)?)?;
-        Ok(())
+        Self::check_id(&conversation.id)?;
+        self.store.write(conversation)
     }
 
     pub fn create_conversation(&self, conversation_id: &str) -> Result<()> {
         let conversation = Conversation::empty(conversation_id);
         self.write_conversation(&conversation)?;
-        // Remove the symlink if there is one already
-        if self.path.join(Self::CURRENT_PATH).exists() {
-            std::fs::remove_file(self.path.join(Self::CURRENT_PATH))?;
+        self.set_current_conversation(conversation_id)
+    }
+
+    pub fn conversation_exists(&self, conversation_id: &str) -> bool {
+        Self::check_id(conversation_id).is_ok() && self.store.exists(conversation_id)
+    }
+
+    /// Checks that an id can name a conversation. Conversations are stored as files named by their ids, alongside
+    /// the store's own files, which are hidden, so an id can't be a path, hidden, or the name of `current`.
+    pub fn check_id(conversation_id: &str) -> Result<()> {
+        if conversation_id.is_empty()
+            || conversation_id.starts_with('.')
+            || conversation_id.contains(['/', '\\'])
+            || conversation_id == Self::CURRENT_PATH
+        {
+            return Err(format!(
+                "Invalid conversation id {conversation_id}: ids can't be empty or `current`, contain path separators or start with `.`"
+            )
+            .into());
         }
-        std::os::unix::fs::symlink(
-            self.path.join(conversation_id),
-            self.path.join(Self::CURRENT_PATH),
-        )?;
         Ok(())
     }
 
+    /// Points `current` at an existing conversation.
+    pub fn switch_conversation(&self, conversation_id: &str) -> Result<()> {
+        Self::check_id(conversation_id)?;
+        if !self.conversation_exists(conversation_id) {
+            let needle = conversation_id.to_lowercase();
+            let close_matches = self
+                .list_conversations()?
+                .into_iter()
+                .map(|c| c.id)
+                .filter(|id| id.to_lowercase().contains(&needle))
+                .collect::<Vec<String>>();
+            return Err(if close_matches.is_empty() {
+                format!("No conversation {conversation_id}")
+            } else {
+                format!(
+                    "No conversation {conversation_id}. Did you mean one of:\n{}",
+                    close_matches.join("\n")
+                )
+            }
+            .into());
+        }
+        self.set_current_conversation(conversation_id)
+    }
+
+    /// Renames the current conversation, returning its old id.
+    pub fn rename_current_conversation(&self, new_id: &str) -> Result<String> {
+        if self.conversation_exists(new_id) {
+            return Err(format!("Conversation {new_id} already exists").into());
+        }
+
+        let mut conversation = self.read_current_conversation()?;
+        let old_id = std::mem::replace(&mut conversation.id, new_id.to_owned());
+        self.write_conversation(&conversation)?;
+        self.set_current_conversation(new_id)?;
+        self.store.remove(&old_id)?;
+        Ok(old_id)
+    }
+
+    /// Copies the current conversation to a new id and makes the copy current, returning the original's id.
+    pub fn branch_current_conversation(&self, new_id: &str) -> Result<String> {
+        if self.conversation_exists(new_id) {
+            return Err(format!("Conversation {new_id} already exists").into());
+        }
+
+        let mut conversation = self.read_current_conversation()?;
+        let old_id = std::mem::replace(&mut conversation.id, new_id.to_owned());
+        self.write_conversation(&conversation)?;
+        self.set_current_conversation(new_id)?;
+        Ok(old_id)
+    }
+
+    /// Deletes a conversation. If it was the current one, `current` is repointed to the most recently modified
+    /// remaining conversation, or removed if none remain. Returns the new current conversation id, if it changed.
+    pub fn delete_conversation(&self, conversation_id: &str) -> Result<Option<String>> {
+        Self::check_id(conversation_id)?;
+        if !self.conversation_exists(conversation_id) {
+            return Err(format!(
+                "No conversation {conversation_id}. Use `conversations` to list them"
+            )
+            .into());
+        }
+
+        let was_current = self.store.current().as_deref() == Some(conversation_id);
+        self.store.remove(conversation_id)?;
+        if !was_current {
+            return Ok(None);
+        }
+
+        match self.list_conversations()?.into_iter().next() {
+            Some(summary) => {
+                self.set_current_conversation(&summary.id)?;
+                Ok(Some(summary.id))
+            }
+            None => {
+                self.store.set_current(None)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn set_current_conversation(&self, conversation_id: &str) -> Result<()> {
+        self.store.set_current(Some(conversation_id))
+    }
+
     // Reads a conversation. If no conversation exists, creates and returns an empty one.
     pub fn read_conversation(&self, conversation_id: &str) -> Result<Conversation> {
-        let file_path = self.path.join(conversation_id);
+        let is_current = conversation_id == Self::CURRENT_PATH;
+        let stored_id = if is_current {
+            self.store.current()
+        } else {
+            Self::check_id(conversation_id)?;
+            Some(conversation_id.to_owned())
+        };
 
-        if !file_path.exists() {
-            let conversation_to_create = if conversation_id.eq(Self::CURRENT_PATH) {
-                &Conversation::create_id("untitled-conversation".to_owned())
-            } else {
-                conversation_id
-            };
-            self.create_conversation(conversation_to_create)?
+        if let Some(stored_id) = stored_id {
+            if let Some(conversation) = self.store.read(&stored_id)? {
+                return Ok(conversation);
+            }
         }
 
-        let bytes = fs::read(file_path)?;
-        Ok(serde_json::from_slice(&bytes)?)
+        let conversation_to_create = if is_current {
+            Conversation::create_id("untitled-conversation".to_owned())
+        } else {
+            conversation_id.to_owned()
+        };
+        self.create_conversation(&conversation_to_create)?;
+        Ok(Conversation::empty(&conversation_to_create))
     }
 
     pub fn read_current_conversation(&self) -> Result<Conversation> {
         self.read_conversation(Self::CURRENT_PATH)
     }
+
+    /// Lists all stored conversations, most recently modified first.
+    pub fn list_conversations(&self) -> Result<Vec<ConversationSummary>> {
+        self.store.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::MessageParts;
+
+    #[test]
+    fn creating_a_second_conversation_keeps_both() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path().to_path_buf()).unwrap();
+
+        for id in ["first", "second"] {
+            db.create_conversation(id).unwrap();
+            let mut conversation = db.read_current_conversation().unwrap();
+            assert_eq!(conversation.id, id);
+            conversation.add_assistant_message(vec![MessageParts::Markdown(format!("In {id}"))]);
+            db.write_conversation(&conversation).unwrap();
+        }
+
+        let mut listed: Vec<String> = db
+            .list_conversations()
+            .unwrap()
+            .into_iter()
+            .map(|summary| summary.id)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, ["first", "second"]);
+
+        for id in ["first", "second"] {
+            let conversation = db.read_conversation(id).unwrap();
+            assert_eq!(conversation.id, id);
+            assert_eq!(
+                conversation.messages[0].parts(),
+                [MessageParts::Markdown(format!("In {id}"))]
+            );
+        }
+    }
 }
@@ -0,0 +1,114 @@
+use std::{fs, path::Path, path::PathBuf};
+
+use serde::{de::IgnoredAny, Deserialize};
+
+use super::{ConversationSummary, Store};
+use crate::model::{Conversation, Result};
+
+// Only the parts of a stored conversation needed for a summary; the rest is skipped during deserialization.
+#[derive(Deserialize)]
+struct ConversationHeader {
+    id: String,
+    messages: Vec<IgnoredAny>,
+}
+
+/// Stores each conversation as a JSON file named by its id, with the current conversation's id in `current`.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    const CURRENT_PATH: &'static str = "current";
+    const CURRENT_TMP_PATH: &'static str = ".current.tmp";
+
+    pub fn open(path: &Path) -> Result<FileStore> {
+        Ok(FileStore {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Store for FileStore {
+    fn read(&self, conversation_id: &str) -> Result<Option<Conversation>> {
+        let file_path = self.path.join(conversation_id);
+        if !file_path.is_file() {
+            return Ok(None);
+        }
+        let bytes = fs::read(file_path)?;
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    // Write alongside the conversation and rename into place, so a reader never sees a partially written file.
+    fn write(&self, conversation: &Conversation) -> Result<()> {
+        let tmp_path = self.path.join(format!(".{}.tmp", conversation.id));
+        fs::write(&tmp_path, serde_json::to_string_pretty(conversation)?)?;
+        fs::rename(&tmp_path, self.path.join(&conversation.id))?;
+        Ok(())
+    }
+
+    fn exists(&self, conversation_id: &str) -> bool {
+        self.path.join(conversation_id).is_file()
+    }
+
+    fn remove(&self, conversation_id: &str) -> Result<()> {
+        fs::remove_file(self.path.join(conversation_id))?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ConversationSummary>> {
+        let mut summaries = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            // Skip the `current` pointer, and hidden files such as the REPL history
+            if file_name == Self::CURRENT_PATH || file_name.starts_with('.') {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            match serde_json::from_slice::<ConversationHeader>(&fs::read(entry.path())?) {
+                Ok(header) => summaries.push(ConversationSummary {
+                    id: header.id,
+                    message_count: header.messages.len(),
+                    modified: metadata.modified()?,
+                }),
+                Err(e) => log::warn!("Skipping unreadable conversation {file_name}: {e}"),
+            }
+        }
+
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.modified));
+        Ok(summaries)
+    }
+
+    fn current(&self) -> Option<String> {
+        let current_path = self.path.join(Self::CURRENT_PATH);
+        // Older versions made `current` a symlink to the conversation file
+        if current_path.symlink_metadata().ok()?.is_symlink() {
+            let target = fs::read_link(current_path).ok()?;
+            return Some(target.file_name()?.to_string_lossy().into_owned());
+        }
+        let conversation_id = fs::read_to_string(current_path).ok()?;
+        Some(conversation_id.trim().to_owned()).filter(|id| !id.is_empty())
+    }
+
+    // Write the new id alongside `current` and rename it into place, so `current` is replaced atomically.
+    fn set_current(&self, conversation_id: Option<&str>) -> Result<()> {
+        let current_path = self.path.join(Self::CURRENT_PATH);
+        let Some(conversation_id) = conversation_id else {
+            if current_path.symlink_metadata().is_ok() {
+                fs::remove_file(current_path)?;
+            }
+            return Ok(());
+        };
+
+        let tmp_path = self.path.join(Self::CURRENT_TMP_PATH);
+        fs::write(&tmp_path, conversation_id)?;
+        fs::rename(&tmp_path, current_path)?;
+        Ok(())
+    }
+}
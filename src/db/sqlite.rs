@@ -0,0 +1,172 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{Map, Value};
+
+use super::{ConversationSummary, Store};
+use crate::model::{Conversation, Result};
+
+// The conversation fields stored in their own columns. Any other fields are stored together as metadata.
+const MESSAGES_FIELD: &str = "messages";
+const CONTEXT_FIELDS: [&str; 2] = ["unseen_context", "seen_context"];
+
+/// Stores conversations in a sqlite database, one row per conversation, with the current conversation's id
+/// in a metadata table.
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    const DB_FILE: &'static str = "conversations.sqlite3";
+    const CURRENT_KEY: &'static str = "current";
+
+    pub fn open(path: &Path) -> Result<SqliteStore> {
+        let connection = Connection::open(path.join(Self::DB_FILE))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                id TEXT PRIMARY KEY,
+                messages TEXT NOT NULL,
+                contexts TEXT NOT NULL,
+                metadata TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS metadata (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );",
+        )?;
+        Ok(SqliteStore { connection })
+    }
+}
+
+fn take_fields(object: &mut Map<String, Value>, fields: &[&str]) -> Value {
+    Value::Object(
+        fields
+            .iter()
+            .filter_map(|field| object.remove_entry(*field))
+            .collect(),
+    )
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.try_into().unwrap_or_default())
+}
+
+impl Store for SqliteStore {
+    fn read(&self, conversation_id: &str) -> Result<Option<Conversation>> {
+        let row = self
+            .connection
+            .query_row(
+                "SELECT messages, contexts, metadata FROM conversations WHERE id = ?1",
+                params![conversation_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((messages, contexts, metadata)) = row else {
+            return Ok(None);
+        };
+
+        let mut object: Map<String, Value> = serde_json::from_str(&metadata)?;
+        object.insert(MESSAGES_FIELD.to_owned(), serde_json::from_str(&messages)?);
+        let contexts: Map<String, Value> = serde_json::from_str(&contexts)?;
+        object.extend(contexts);
+        Ok(Some(serde_json::from_value(Value::Object(object))?))
+    }
+
+    fn write(&self, conversation: &Conversation) -> Result<()> {
+        let Value::Object(mut object) = serde_json::to_value(conversation)? else {
+            return Err("Conversation did not serialize to an object".into());
+        };
+        let messages = object.remove(MESSAGES_FIELD).unwrap_or_default();
+        let contexts = take_fields(&mut object, &CONTEXT_FIELDS);
+        let updated_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+
+        self.connection.execute(
+            "INSERT INTO conversations (id, messages, contexts, metadata, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (id) DO UPDATE SET
+                messages = excluded.messages,
+                contexts = excluded.contexts,
+                metadata = excluded.metadata,
+                updated_at = excluded.updated_at",
+            params![
+                conversation.id,
+                messages.to_string(),
+                contexts.to_string(),
+                Value::Object(object).to_string(),
+                updated_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn exists(&self, conversation_id: &str) -> bool {
+        self.connection
+            .query_row(
+                "SELECT 1 FROM conversations WHERE id = ?1",
+                params![conversation_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .is_ok_and(|row| row.is_some())
+    }
+
+    fn remove(&self, conversation_id: &str) -> Result<()> {
+        self.connection.execute(
+            "DELETE FROM conversations WHERE id = ?1",
+            params![conversation_id],
+        )?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ConversationSummary>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, json_array_length(messages), updated_at FROM conversations
+            ORDER BY updated_at DESC",
+        )?;
+        let summaries = statement
+            .query_map([], |row| {
+                Ok(ConversationSummary {
+                    id: row.get(0)?,
+                    message_count: row.get::<_, i64>(1)?.try_into().unwrap_or_default(),
+                    modified: from_millis(row.get(2)?),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ConversationSummary>>>()?;
+        Ok(summaries)
+    }
+
+    fn current(&self) -> Option<String> {
+        self.connection
+            .query_row(
+                "SELECT value FROM metadata WHERE key = ?1",
+                params![Self::CURRENT_KEY],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    fn set_current(&self, conversation_id: Option<&str>) -> Result<()> {
+        match conversation_id {
+            Some(conversation_id) => self.connection.execute(
+                "INSERT INTO metadata (key, value) VALUES (?1, ?2)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                params![Self::CURRENT_KEY, conversation_id],
+            )?,
+            None => self.connection.execute(
+                "DELETE FROM metadata WHERE key = ?1",
+                params![Self::CURRENT_KEY],
+            )?,
+        };
+        Ok(())
+    }
+}
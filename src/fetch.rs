@@ -0,0 +1,190 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io::Read,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{
+    blocking::{Client, Response},
+    header::CONTENT_TYPE,
+};
+use scraper::{Html, Selector};
+
+use crate::{db::claippy_dir, model::Result};
+
+const CACHE_DIR: &str = "cache";
+// Page chrome that isn't part of a page's content.
+const HTML_CHROME: &str =
+    "script, style, noscript, template, svg, nav, header, footer, aside, form";
+// Where a page's main content is likely to be, in order of preference.
+const HTML_CONTENT: [&str; 4] = ["main", "article", "[role=main]", "body"];
+const HTML_TEXT_WIDTH: usize = 100;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 10 * 1024 * 1024;
+// Content types that can't usefully be sent as text.
+const BINARY_CONTENT_TYPES: [&str; 11] = [
+    "image/",
+    "audio/",
+    "video/",
+    "font/",
+    "application/octet-stream",
+    "application/pdf",
+    "application/zip",
+    "application/gzip",
+    "application/x-tar",
+    "application/x-7z-compressed",
+    "application/wasm",
+];
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Fetches the body of a URL, converting HTML pages to text. Bodies are cached under `.claippy/cache`, and a cached
+/// body is used if it was fetched within the last `CLAIPPY_URL_CACHE_TTL` seconds (default one day).
+pub fn fetch_url(url: &str) -> Result<String> {
+    let cache_path = cache_path(url);
+    if let Some(path) = &cache_path {
+        if is_fresh(path) {
+            match fs::read_to_string(path) {
+                Ok(body) => return Ok(body),
+                Err(e) => log::warn!("Could not read cached {url}: {e}"),
+            }
+        }
+    }
+
+    let response = Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Could not fetch {url}: {e}"))?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if BINARY_CONTENT_TYPES
+        .iter()
+        .any(|binary| content_type.starts_with(binary))
+    {
+        return Err(
+            format!("{url} has content type {content_type}, which is not text. Use `rm` to remove it from the context").into(),
+        );
+    }
+
+    let body = read_limited(response, url)?;
+    let body = if content_type.starts_with("text/html") {
+        html_to_text(&body)?
+    } else {
+        body
+    };
+    if let Some(path) = cache_path {
+        if let Err(e) =
+            fs::create_dir_all(path.parent().unwrap_or(&path)).and_then(|_| fs::write(&path, &body))
+        {
+            log::warn!("Could not cache {url}: {e}");
+        }
+    }
+    Ok(body)
+}
+
+/// Removes any cached body for a URL, so it is fetched again. Returns whether one was cached.
+pub fn evict_cached_url(url: &str) -> Result<bool> {
+    match cache_path(url) {
+        Some(path) if path.is_file() => {
+            fs::remove_file(path)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The maximum size of a URL's body, from `CLAIPPY_MAX_DOWNLOAD_BYTES`. Larger downloads are abandoned.
+fn max_download_bytes() -> u64 {
+    std::env::var("CLAIPPY_MAX_DOWNLOAD_BYTES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_DOWNLOAD_BYTES)
+}
+
+// Reads a response body as text, failing as soon as it is larger than the download limit, even if the server
+// didn't say how large it is.
+fn read_limited(response: Response, url: &str) -> Result<String> {
+    let max_bytes = max_download_bytes();
+    let too_large = || {
+        format!("{url} is larger than the {max_bytes} byte download limit. Use `rm` to remove it from the context")
+    };
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes)
+    {
+        return Err(too_large().into());
+    }
+
+    let mut bytes = Vec::new();
+    response.take(max_bytes + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > max_bytes {
+        return Err(too_large().into());
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The text of an HTML page's main content, formatted as markdown-like text, without scripts or navigation.
+fn html_to_text(html: &str) -> Result<String> {
+    let mut document = Html::parse_document(html);
+    let chrome = Selector::parse(HTML_CHROME).unwrap();
+    let chrome_ids: Vec<_> = document
+        .select(&chrome)
+        .map(|element| element.id())
+        .collect();
+    for id in chrome_ids {
+        if let Some(mut node) = document.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+
+    let content = HTML_CONTENT
+        .iter()
+        .find_map(|selector| {
+            let selector = Selector::parse(selector).unwrap();
+            document
+                .select(&selector)
+                .next()
+                .map(|element| element.html())
+        })
+        .unwrap_or_else(|| document.html());
+    Ok(html2text::from_read(content.as_bytes(), HTML_TEXT_WIDTH)?)
+}
+
+fn cache_ttl() -> Duration {
+    std::env::var("CLAIPPY_URL_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CACHE_TTL)
+}
+
+fn is_fresh(path: &PathBuf) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age < cache_ttl())
+}
+
+// Cached bodies are named by a hash of their URL. The hash isn't guaranteed to be stable between Rust
+// versions, but a changed hash only means a URL is fetched again.
+fn cache_path(url: &str) -> Option<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    let dir = claippy_dir()
+        .map_err(|e| log::warn!("Not caching {url}: {e}"))
+        .ok()?;
+    Some(
+        dir.join(CACHE_DIR)
+            .join(format!("{:016x}", hasher.finish())),
+    )
+}
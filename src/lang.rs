@@ -0,0 +1,127 @@
+struct Language {
+    /// The language's name, then the other names it often goes by, all lowercase.
+    names: &'static [&'static str],
+    /// Its file extensions, the usual one first.
+    extensions: &'static [&'static str],
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust"],
+        extensions: &["rs"],
+    },
+    Language {
+        names: &["python", "python3", "py3"],
+        extensions: &["py", "pyw"],
+    },
+    Language {
+        names: &["javascript", "node"],
+        extensions: &["js", "mjs", "cjs", "jsx"],
+    },
+    Language {
+        names: &["typescript"],
+        extensions: &["ts", "tsx", "mts", "cts"],
+    },
+    Language {
+        names: &["bash", "shell", "zsh", "console", "shell-session"],
+        extensions: &["sh", "bash", "zsh"],
+    },
+    Language {
+        names: &["c"],
+        extensions: &["c", "h"],
+    },
+    Language {
+        names: &["c++"],
+        extensions: &["cpp", "cc", "cxx", "hpp", "hh"],
+    },
+    Language {
+        names: &["java"],
+        extensions: &["java"],
+    },
+    Language {
+        names: &["go", "golang"],
+        extensions: &["go"],
+    },
+    Language {
+        names: &["ruby"],
+        extensions: &["rb"],
+    },
+    Language {
+        names: &["json"],
+        extensions: &["json"],
+    },
+    Language {
+        names: &["yaml"],
+        extensions: &["yaml", "yml"],
+    },
+    Language {
+        names: &["toml"],
+        extensions: &["toml"],
+    },
+    Language {
+        names: &["html"],
+        extensions: &["html", "htm"],
+    },
+    Language {
+        names: &["css"],
+        extensions: &["css"],
+    },
+    Language {
+        names: &["sql"],
+        extensions: &["sql"],
+    },
+    Language {
+        names: &["markdown"],
+        extensions: &["md", "markdown"],
+    },
+];
+
+/// The usual file extension for a language, given its name (`rust`), another name it goes by (`golang`) or one of
+/// its extensions (`py`), in any case.
+pub fn extension_for_language(language: &str) -> Option<&'static str> {
+    let language = language.to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|known| {
+            known.names.contains(&language.as_str())
+                || known.extensions.contains(&language.as_str())
+        })
+        .map(|known| known.extensions[0])
+}
+
+/// The name of the language a file extension (without its `.`) is for, in any case.
+pub fn language_for_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|known| known.extensions.contains(&extension.as_str()))
+        .map(|known| known.names[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn languages_and_extensions_are_looked_up_in_any_case() {
+        let extensions = [
+            ("golang", Some("go")),
+            ("Rust", Some("rs")),
+            ("PY", Some("py")),
+            ("shell-session", Some("sh")),
+            ("unknown", None),
+        ];
+        for (language, extension) in extensions {
+            assert_eq!(extension_for_language(language), extension, "{language}");
+        }
+        let languages = [
+            ("YML", Some("yaml")),
+            ("tsx", Some("typescript")),
+            ("hh", Some("c++")),
+            ("", None),
+        ];
+        for (extension, language) in languages {
+            assert_eq!(language_for_extension(extension), language, "{extension}");
+        }
+    }
+}
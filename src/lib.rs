@@ -1,5 +1,10 @@
 pub mod command;
+pub mod completions;
+pub mod config;
 pub mod db;
+pub mod fetch;
+pub mod lang;
 pub mod model;
 pub mod query;
+pub mod render;
 pub mod repl;
@@ -1,38 +1,387 @@
 use claippy::{
     command::{CliCmd, CmdOutput, Command},
+    completions,
+    config::Config,
     db::Db,
-    query::{Bedrock, BedrockConfig},
+    model::set_command_timeout,
+    query::{
+        clamp_sampling_parameter, Bedrock, BedrockConfig, Lazy, Ollama, OllamaConfig, OpenAiChat,
+        OpenAiConfig, Traced, DEFAULT_REGION, MAX_TEMPERATURE, MAX_TOP_P,
+    },
+    render::{ArtifactLayout, RenderOptions, DEFAULT_THEME},
 };
-use std::{env, error::Error, process};
+use colored::Colorize;
+use log::LevelFilter;
+use std::{env, error::Error, fmt, fs, path::PathBuf, process, str::FromStr, time::Duration};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
-
-    let mut args = env::args();
-    args.next(); // discard the process name itself
+// The log level is taken from -v or -q, then CLAIPPY_LOG or RUST_LOG (which can also filter by module, e.g.
+// `claippy::query=debug`), and is errors only by default.
+fn init_logger(level: Option<LevelFilter>) {
+    let mut builder = env_logger::Builder::new();
+    match level {
+        Some(level) => builder.filter_level(level),
+        None => builder.parse_filters(
+            &env::var("CLAIPPY_LOG")
+                .or_else(|_| env::var("RUST_LOG"))
+                .unwrap_or_else(|_| "error".to_owned()),
+        ),
+    };
+    builder.init();
+}
 
-    let cmd = CliCmd::parse_args(args).unwrap_or_else(|err| {
+fn main() -> Result<(), Box<dyn Error>> {
+    let (flags, cmd) = CliCmd::parse_cli(env::args()).unwrap_or_else(|err| {
+        // Usage errors, and --help, are printed by clap
+        if let Some(err) = err.downcast_ref::<clap::Error>() {
+            err.exit();
+        }
+        init_logger(None);
         log::error!("Error parsing arguments: {err}");
         process::exit(1);
     });
 
-    log::info!("Command: {:?}", cmd);
+    init_logger(flags.log_level);
+    log::info!("Flags: {:?}, Command: {:?}", flags, cmd);
 
-    let db = Db::create()?;
+    // Completion scripts are usually generated from a shell's startup file, outside of any repository
+    if let CliCmd::Completions { shell } = cmd {
+        print!("{}", completions::script(shell)?);
+        return Ok(());
+    }
+
+    let config = Config::load()?;
 
-    let config = BedrockConfig {
-        model_id: "anthropic.claude-3-5-sonnet-20241022-v2:0", //"anthropic.claude-3-5-sonnet-20240620-v1:0",
-        system_prompt: system_prompt(),
-        temperature: 0.1,
-        top_p: 0.9,
-        region: "us-west-2",
-        aws_profile_name: "dev",
+    let theme = setting("CLAIPPY_THEME", config.theme.clone(), DEFAULT_THEME);
+    let show_thinking = first_set(
+        [
+            (
+                env::var_os("CLAIPPY_SHOW_THINKING")
+                    .filter(|value| !value.is_empty())
+                    .map(|_| true),
+                Source::Env("CLAIPPY_SHOW_THINKING"),
+            ),
+            (config.show_thinking, Source::ConfigFile),
+        ],
+        false,
+    );
+    let artifact_headers = first_set(
+        [
+            (
+                flags.no_artifact_headers.then_some(false),
+                Source::Flag("--no-artifact-headers"),
+            ),
+            (config.artifact_headers, Source::ConfigFile),
+        ],
+        true,
+    );
+    let line_numbers = first_set(
+        [
+            (
+                flags.line_numbers.then_some(true),
+                Source::Flag("--line-numbers"),
+            ),
+            (config.line_numbers, Source::ConfigFile),
+        ],
+        false,
+    );
+    let render = RenderOptions::create(
+        flags.no_color,
+        flags.json,
+        flags.raw,
+        Some(theme.value.clone()),
+        show_thinking.value,
+        ArtifactLayout {
+            headers: artifact_headers.value,
+            line_numbers: line_numbers.value,
+        },
+        flags.width,
+    );
+    if !render.color {
+        colored::control::set_override(false);
+    }
+
+    // Each setting is taken from its command line flag, if it has one, then its environment variable, then the
+    // config file, then the default.
+    let model_id = |default: &str| {
+        first_set(
+            [
+                (flags.model_id.clone(), Source::Flag("--model")),
+                (
+                    env::var("CLAIPPY_MODEL_ID").ok(),
+                    Source::Env("CLAIPPY_MODEL_ID"),
+                ),
+                (config.model_id.clone(), Source::ConfigFile),
+            ],
+            default.to_owned(),
+        )
+    };
+    let temperature = || {
+        first_set(
+            [
+                (flags.temperature, Source::Flag("--temperature")),
+                (config.temperature, Source::ConfigFile),
+            ],
+            DEFAULT_TEMPERATURE,
+        )
+        .map(|temperature| clamp_sampling_parameter("temperature", temperature, MAX_TEMPERATURE))
+    };
+    let top_p = || {
+        first_set(
+            [
+                (flags.top_p, Source::Flag("--top-p")),
+                (config.top_p, Source::ConfigFile),
+            ],
+            DEFAULT_TOP_P,
+        )
+        .map(|top_p| clamp_sampling_parameter("top_p", top_p, MAX_TOP_P))
+    };
+    let max_tokens = || {
+        first_set(
+            [(config.max_tokens, Source::ConfigFile)],
+            DEFAULT_MAX_TOKENS,
+        )
+    };
+
+    let stop_sequences = || {
+        first_set(
+            [
+                (
+                    Some(flags.stop_sequences.clone()).filter(|stops| !stops.is_empty()),
+                    Source::Flag("--stop"),
+                ),
+                (config.stop_sequences.clone(), Source::ConfigFile),
+            ],
+            Vec::new(),
+        )
     };
 
-    let model = Bedrock::create(config)?;
+    let system_prompt_file = || {
+        first_set(
+            [
+                (
+                    flags.system_prompt_file.clone().map(Some),
+                    Source::Flag("--system-prompt-file"),
+                ),
+                (
+                    env::var("CLAIPPY_SYSTEM_PROMPT_FILE").ok().map(Some),
+                    Source::Env("CLAIPPY_SYSTEM_PROMPT_FILE"),
+                ),
+                (
+                    config.system_prompt_file.clone().map(Some),
+                    Source::ConfigFile,
+                ),
+            ],
+            None,
+        )
+    };
+    let system_prompt = || -> Result<String, Box<dyn Error>> {
+        match system_prompt_file().value {
+            Some(path) => Ok(fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read system prompt file {path}: {e}"))?),
+            None => Ok(default_system_prompt().to_owned()),
+        }
+    };
+
+    // With no region set, the standard AWS environment is used
+    let region = || {
+        first_set(
+            [
+                (
+                    env::var("CLAIPPY_REGION").ok().map(Some),
+                    Source::Env("CLAIPPY_REGION"),
+                ),
+                (config.region.clone().map(Some), Source::ConfigFile),
+            ],
+            None,
+        )
+    };
+    // With no profile set, the default credential chain is used
+    let aws_profile = || {
+        first_set(
+            [
+                (
+                    env::var("CLAIPPY_AWS_PROFILE").ok().map(Some),
+                    Source::Env("CLAIPPY_AWS_PROFILE"),
+                ),
+                (config.aws_profile.clone().map(Some), Source::ConfigFile),
+            ],
+            None,
+        )
+        .map(|profile| profile.filter(|profile| !profile.is_empty()))
+    };
+    let max_retries = || {
+        parsed_setting(
+            "CLAIPPY_MAX_RETRIES",
+            config.max_retries,
+            DEFAULT_MAX_RETRIES,
+        )
+    };
+    let thinking_budget = || {
+        parsed_setting(
+            "CLAIPPY_THINKING_BUDGET",
+            config.thinking_budget,
+            DEFAULT_THINKING_BUDGET,
+        )
+    };
+    let timeout_secs = || {
+        parsed_setting(
+            "CLAIPPY_TIMEOUT_SECS",
+            config.timeout_secs,
+            DEFAULT_TIMEOUT_SECS,
+        )
+    };
+    let openai_base_url = || {
+        setting(
+            "OPENAI_BASE_URL",
+            config.openai_base_url.clone(),
+            DEFAULT_OPENAI_BASE_URL,
+        )
+    };
+    let ollama_host = || {
+        setting(
+            "OLLAMA_HOST",
+            config.ollama_host.clone(),
+            DEFAULT_OLLAMA_HOST,
+        )
+    };
+
+    let backend = setting("CLAIPPY_BACKEND", config.backend.clone(), "bedrock");
+    let default_model_id = match backend.value.as_str() {
+        "bedrock" => DEFAULT_MODEL_ID,
+        "openai" => DEFAULT_OPENAI_MODEL,
+        "ollama" => DEFAULT_OLLAMA_MODEL,
+        other => return Err(format!("Unknown backend: {other}").into()),
+    };
+
+    if let CliCmd::Config = cmd {
+        let mut settings = vec![
+            ("backend", backend.value.clone(), backend.source),
+            model_id(default_model_id).describe("model_id"),
+            temperature().describe("temperature"),
+            top_p().describe("top_p"),
+            stop_sequences()
+                .map(|stops| format!("{stops:?}"))
+                .describe("stop_sequences"),
+            system_prompt_file()
+                .map(|path| path.unwrap_or_else(|| "none (the built-in prompt)".to_owned()))
+                .describe("system_prompt_file"),
+        ];
+        match backend.value.as_str() {
+            "bedrock" => settings.extend([
+                max_tokens().describe("max_tokens"),
+                region()
+                    .map(|region| {
+                        region.unwrap_or_else(|| {
+                            format!("none (AWS_REGION or the profile's, else {DEFAULT_REGION})")
+                        })
+                    })
+                    .describe("region"),
+                aws_profile()
+                    .map(|profile| {
+                        profile.unwrap_or_else(|| "none (the default credential chain)".to_owned())
+                    })
+                    .describe("aws_profile"),
+                max_retries()?.describe("max_retries"),
+                thinking_budget()?
+                    .map(|budget| match budget {
+                        0 => "0 (thinking is off)".to_owned(),
+                        budget => budget.to_string(),
+                    })
+                    .describe("thinking_budget"),
+            ]),
+            // Only whether the key is set, never the key itself
+            "openai" => settings.extend([
+                openai_base_url().describe("openai_base_url"),
+                (
+                    "OPENAI_API_KEY",
+                    if env::var_os("OPENAI_API_KEY").is_some() {
+                        "set"
+                    } else {
+                        "not set"
+                    }
+                    .to_owned(),
+                    Source::Env("OPENAI_API_KEY"),
+                ),
+            ]),
+            _ => settings.push(ollama_host().describe("ollama_host")),
+        }
+        settings.extend([
+            timeout_secs()?.describe("timeout_secs"),
+            theme.describe("theme"),
+            show_thinking.describe("show_thinking"),
+            artifact_headers.describe("artifact_headers"),
+            line_numbers.describe("line_numbers"),
+        ]);
+        println!("{}", describe_settings(&settings));
+        return Ok(());
+    }
+
+    // The model is only created once a command queries it, so the commands that don't query it work without the
+    // backend's credentials.
+    let model = match backend.value.as_str() {
+        "bedrock" => Lazy::new(|| {
+            let config = BedrockConfig {
+                model_id: model_id(default_model_id).value,
+                system_prompt: system_prompt()?,
+                temperature: temperature().value,
+                top_p: top_p().value,
+                max_tokens: max_tokens().value,
+                stop_sequences: stop_sequences().value,
+                region: region().value,
+                aws_profile_name: aws_profile().value,
+                max_retries: max_retries()?.value,
+                timeout: Some(timeout_secs()?.value)
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+                thinking_budget: Some(thinking_budget()?.value).filter(|budget| *budget > 0),
+            };
+            Ok(Box::new(Bedrock::create(config)?))
+        }),
+        "openai" => Lazy::new(|| {
+            let config = OpenAiConfig {
+                model: model_id(default_model_id).value,
+                base_url: openai_base_url().value,
+                api_key: env::var("OPENAI_API_KEY").ok(),
+                system_prompt: system_prompt()?,
+                temperature: temperature().value,
+                top_p: top_p().value,
+                stop_sequences: stop_sequences().value,
+            };
+            Ok(Box::new(OpenAiChat::create(config)?))
+        }),
+        _ => Lazy::new(|| {
+            let config = OllamaConfig {
+                model: model_id(default_model_id).value,
+                host: ollama_host().value,
+                system_prompt: system_prompt()?,
+                temperature: temperature().value,
+                top_p: top_p().value,
+                stop_sequences: stop_sequences().value,
+            };
+            Ok(Box::new(Ollama::create(config)?))
+        }),
+    };
 
-    match cmd.execute(&model, &db)? {
+    // Context commands are stopped after the same timeout as a response
+    set_command_timeout(
+        Some(timeout_secs()?.value)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    );
+    let db = Db::create()?;
+
+    let output = cmd.execute(
+        &Traced {
+            model: &model,
+            dir: trace_dir(&db),
+        },
+        &db,
+        &render,
+    )?;
+
+    match output {
         CmdOutput::Message(msg) => print!("{}", msg),
+        CmdOutput::Json(value) => print!("{}", value),
         CmdOutput::Done => (), // do nothing
     }
 
@@ -41,7 +390,142 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn system_prompt() -> &'static str {
+const DEFAULT_MODEL_ID: &str = "anthropic.claude-3-5-sonnet-20241022-v2:0"; //"anthropic.claude-3-5-sonnet-20240620-v1:0",
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_THINKING_BUDGET: u32 = 0;
+const DEFAULT_MAX_TOKENS: i32 = 4096; // the maximum
+const DEFAULT_TEMPERATURE: f32 = 0.1;
+const DEFAULT_TOP_P: f32 = 0.9;
+const DEFAULT_OPENAI_MODEL: &str = "gpt-4o";
+const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_OLLAMA_MODEL: &str = "llama3.1";
+const DEFAULT_OLLAMA_HOST: &str = "http://localhost:11434";
+
+/// Where a setting was taken from, as printed by `config`.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Flag(&'static str),
+    Env(&'static str),
+    ConfigFile,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag(flag) => write!(f, "{flag}"),
+            Source::Env(key) => write!(f, "${key}"),
+            Source::ConfigFile => write!(f, "config file"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// A setting's value, and where it was taken from.
+struct Setting<T> {
+    value: T,
+    source: Source,
+}
+
+impl<T> Setting<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Setting<U> {
+        Setting {
+            value: f(self.value),
+            source: self.source,
+        }
+    }
+}
+
+impl<T: fmt::Display> Setting<T> {
+    // A row of the `config` listing.
+    fn describe(&self, name: &'static str) -> (&'static str, String, Source) {
+        (name, self.value.to_string(), self.source)
+    }
+}
+
+/// The first of the candidate values that is set, in order of precedence, or else the default.
+fn first_set<T>(
+    candidates: impl IntoIterator<Item = (Option<T>, Source)>,
+    default: T,
+) -> Setting<T> {
+    candidates
+        .into_iter()
+        .find_map(|(value, source)| {
+            Some(Setting {
+                value: value?,
+                source,
+            })
+        })
+        .unwrap_or(Setting {
+            value: default,
+            source: Source::Default,
+        })
+}
+
+/// A setting from the environment variable `key`, then the config file, then the default.
+fn setting(key: &'static str, configured: Option<String>, default: &str) -> Setting<String> {
+    first_set(
+        [
+            (env::var(key).ok(), Source::Env(key)),
+            (configured, Source::ConfigFile),
+        ],
+        default.to_owned(),
+    )
+}
+
+/// Like `setting`, for settings that are parsed from the environment variable.
+fn parsed_setting<T: FromStr>(
+    key: &'static str,
+    configured: Option<T>,
+    default: T,
+) -> Result<Setting<T>, Box<dyn Error>>
+where
+    T::Err: Error + 'static,
+{
+    match env::var(key) {
+        Ok(value) => Ok(Setting {
+            value: value.parse()?,
+            source: Source::Env(key),
+        }),
+        Err(_) => Ok(first_set([(configured, Source::ConfigFile)], default)),
+    }
+}
+
+/// The `config` listing: a line for each setting, with its value and where it was taken from.
+fn describe_settings(settings: &[(&str, String, Source)]) -> String {
+    let path = Config::path().map_or("none".to_owned(), |path| path.display().to_string());
+    let name_width = settings
+        .iter()
+        .map(|(name, ..)| name.len())
+        .max()
+        .unwrap_or(0);
+    let value_width = settings
+        .iter()
+        .map(|(_, value, _)| value.len())
+        .max()
+        .unwrap_or(0);
+    let mut listing = format!("Config file: {path}\n");
+    for (name, value, source) in settings {
+        listing += &format!(
+            "  {name:name_width$}  {value:value_width$}  {}\n",
+            format!("({source})").dimmed()
+        );
+    }
+    listing.trim_end().to_owned()
+}
+
+/// Where to trace requests and responses, if `CLAIPPY_TRACE_DIR` is set: that directory, or `.claippy/trace` if
+/// it is `1` or `true`.
+fn trace_dir(db: &Db) -> Option<PathBuf> {
+    match env::var("CLAIPPY_TRACE_DIR").ok()?.as_str() {
+        "" => None,
+        "1" | "true" => Some(db.path().join("trace")),
+        dir => Some(PathBuf::from(dir)),
+    }
+}
+
+fn default_system_prompt() -> &'static str {
     r###"
     The assistant is claippy, an expert coding and software design assistant. It provides expert-level but concise responses to
     user requests.
@@ -63,7 +547,14 @@ fn system_prompt() -> &'static str {
 
     The assistant may be provided with <ClaippyContext> tags that provide files that the user is providing as context. Often, these
     will be source code or documentation files relevant to the current software design. They will have a `src` attribute describing the file
-    location (file system path or public URL), and the content of the element will be the content of the file.
+    location (file system path or public URL), and the content of the element will be the content of the file. If they have a `lines`
+    attribute, e.g. `lines="40-120"`, the content is only that range of lines of the file. If they have a `type="tree"` attribute, the
+    content is a listing of the files in that directory, rather than file contents. If they have a `type="artifact"` attribute, the content
+    is the latest version of the artifact that the user referred to by its identifier, e.g. `@example-code-snippet`. If they have a
+    `type="image"` attribute, the image itself is attached to the message. If they have a `type="command"` attribute, the `src` is
+    a shell command prefixed with `$`, and the content is its output. If they have a `type="diff"` attribute, the content is a
+    unified diff of the user's uncommitted changes, from `git diff` or `git diff --staged`. A `language` attribute, e.g.
+    `language="rust"`, gives the language of the content. A closing `</ClaippyContext>` tag inside the content is escaped as `<\/ClaippyContext>`.
 
     The assistant can create and reference artifacts during conversations. Artifacts are for substantial content that may be reusable by the user.
 
@@ -1,144 +1,965 @@
-use std::{error::Error, fmt::Debug, time::Instant};
+use std::{
+    cell::OnceCell,
+    error::Error,
+    fmt::Debug,
+    fs::{self, File},
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use aws_config::{
+    default_provider::region::DefaultRegionChain, meta::region::RegionProviderChain,
+    retry::RetryConfig, Region,
+};
+use aws_credential_types::provider::error::CredentialsError;
 use aws_sdk_bedrockruntime::{
-    error::SdkError,
+    error::{DisplayErrorContext, ProvideErrorMetadata, SdkError},
+    operation::invoke_model_with_response_stream::InvokeModelWithResponseStreamError,
     primitives::Blob,
     types::{error::ResponseStreamError, PayloadPart, ResponseStream},
     Client,
 };
+use chrono::Utc;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::runtime::Runtime;
+use serde_json::Value;
+use tokio::runtime::{self, Runtime};
+
+use crate::model::{Message, Messages, Result, ResultIterator, TokenUsage};
 
-use crate::model::{Message, Messages, Result, ResultIterator};
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Serialize)]
-struct ReqBody {
+struct ReqBody<'a> {
     anthropic_version: &'static str,
     max_tokens: i32,
     temperature: f32,
-    top_p: f32,
-    system: &'static str,
-    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    system: &'a str,
+    messages: Vec<BedrockMessage>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<ReqThinking>,
+}
+
+#[derive(Serialize)]
+struct ReqThinking {
+    #[serde(rename = "type")]
+    thinking_type: &'static str,
+    budget_tokens: u32,
+}
+
+#[derive(Serialize)]
+struct BedrockMessage {
+    role: String,
+    content: BedrockContent,
+}
+
+/// A message's content: plain text, or content blocks when it has images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BedrockContent {
+    Text(String),
+    Blocks(Vec<BedrockContentBlock>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum BedrockContentBlock {
+    Image { source: BedrockImageSource },
+    Text { text: String },
+}
+
+#[derive(Serialize)]
+struct BedrockImageSource {
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    media_type: String,
+    data: String,
+}
+
+impl From<Message> for BedrockMessage {
+    fn from(message: Message) -> Self {
+        let content = if message.images.is_empty() {
+            BedrockContent::Text(message.content)
+        } else {
+            let mut blocks: Vec<BedrockContentBlock> = message
+                .images
+                .into_iter()
+                .map(|image| BedrockContentBlock::Image {
+                    source: BedrockImageSource {
+                        source_type: "base64",
+                        media_type: image.media_type,
+                        data: image.data,
+                    },
+                })
+                .collect();
+            blocks.push(BedrockContentBlock::Text {
+                text: message.content,
+            });
+            BedrockContent::Blocks(blocks)
+        };
+        BedrockMessage {
+            role: message.role,
+            content,
+        }
+    }
 }
 
+/// A single item of a streamed model response.
+#[derive(Debug)]
+pub enum ResponseChunk {
+    Text(String),
+    /// The model's reasoning before its response, from models with extended thinking.
+    Thinking(String),
+    Usage(TokenUsage),
+    Stop(StopReason),
+}
+
+/// Why the model stopped generating a response.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The response hit the output token limit, so it is incomplete.
+    MaxTokens,
+    /// The model generated one of the requested stop sequences, which isn't included in the response.
+    StopSequence(Option<String>),
+    Other(String),
+}
+
+/// The highest temperature any backend accepts: the OpenAI and Ollama APIs accept 0-2, and Bedrock only 0-1.
+pub const MAX_TEMPERATURE: f32 = 2.0;
+/// The highest top_p, which is a probability.
+pub const MAX_TOP_P: f32 = 1.0;
+const BEDROCK_MAX_TEMPERATURE: f32 = 1.0;
+
+/// Clamps a temperature or top_p value to between 0 and `max`, warning if it was outside it.
+pub fn clamp_sampling_parameter(name: &str, value: f32, max: f32) -> f32 {
+    let clamped = if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, max)
+    };
+    if clamped != value {
+        eprintln!("Warning: {name} {value} is outside 0-{max}, using {clamped}");
+    }
+    clamped
+}
+
+/// Receives each payload of a response as the backend received it, before it's parsed: a JSON object from Bedrock,
+/// or a line of the stream from the OpenAI and Ollama APIs.
+pub type RawSink<'a> = Box<dyn FnMut(&[u8]) + 'a>;
+
 /// Queryable provides the interface that any LLM being queried should implement.
 pub trait Queryable {
-    fn generate(&self, query: Messages) -> ResultIterator<Result<String>>;
+    fn generate(&self, query: Messages) -> ResultIterator<'_, Result<ResponseChunk>> {
+        self.generate_raw(query, Box::new(|_| {}))
+    }
+
+    /// Like `generate`, also passing each raw payload of the response to `raw`, for tracing.
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>>;
+
+    /// The serialized request body that `generate` sends for the query.
+    fn request_body(&self, query: Messages) -> Result<String>;
+
+    /// Generates the whole response text at once, for callers that don't stream it.
+    fn generate_blocking(&self, query: Messages) -> Result<String> {
+        let mut content = String::new();
+        for chunk in self.generate(query)? {
+            if let ResponseChunk::Text(text) = chunk? {
+                content.push_str(&text);
+            }
+        }
+        Ok(content)
+    }
 }
 
+/// Queries a model with a different temperature than it was configured with, e.g. for a REPL session.
+pub struct WithTemperature<'a, Q: Queryable + ?Sized> {
+    pub model: &'a Q,
+    pub temperature: Option<f32>,
+}
+
+impl<Q: Queryable + ?Sized> WithTemperature<'_, Q> {
+    fn apply(&self, mut query: Messages) -> Messages {
+        query.temperature = query.temperature.or(self.temperature);
+        query
+    }
+}
+
+impl<Q: Queryable + ?Sized> Queryable for WithTemperature<'_, Q> {
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        self.model.generate_raw(self.apply(query), raw)
+    }
+
+    fn request_body(&self, query: Messages) -> Result<String> {
+        self.model.request_body(self.apply(query))
+    }
+}
+
+/// Writes each request body, the raw payloads of its response, and the chunks parsed from them, to timestamped
+/// files in a directory, for debugging a response that parsed wrong or context that was malformed. Only the body
+/// is written: credentials are sent in headers, which aren't.
+pub struct Traced<'a, Q: Queryable + ?Sized> {
+    pub model: &'a Q,
+    /// The directory to write to, or `None` to not trace.
+    pub dir: Option<PathBuf>,
+}
+
+// Appends to a trace file, which is abandoned if it can't be written.
+fn trace_writer(mut file: Option<File>) -> impl FnMut(&[u8]) {
+    move |bytes| {
+        if let Some(f) = file.as_mut() {
+            if let Err(e) = f.write_all(bytes) {
+                log::warn!("Could not write trace: {e}");
+                file = None;
+            }
+        }
+    }
+}
+
+impl<Q: Queryable + ?Sized> Queryable for Traced<'_, Q> {
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        let Some(dir) = &self.dir else {
+            return self.model.generate_raw(query, raw);
+        };
+
+        let name = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let create = |suffix: &str| {
+            File::create(dir.join(format!("{name}-{suffix}")))
+                .inspect_err(|e| log::warn!("Could not write trace to {}: {e}", dir.display()))
+                .ok()
+        };
+        let written = fs::create_dir_all(dir).and_then(|_| {
+            fs::write(
+                dir.join(format!("{name}-request.json")),
+                self.model.request_body(query.clone()).unwrap_or_default(),
+            )
+        });
+        let (raw_file, response_file) = match written {
+            Ok(()) => (create("raw.txt"), create("response.txt")),
+            Err(e) => {
+                log::warn!("Could not write trace to {}: {e}", dir.display());
+                (None, None)
+            }
+        };
+
+        let mut raw = raw;
+        let mut trace_raw = trace_writer(raw_file);
+        let response = self.model.generate_raw(
+            query,
+            Box::new(move |bytes| {
+                trace_raw(bytes);
+                raw(bytes);
+            }),
+        );
+        let mut write_response = trace_writer(response_file);
+        let mut trace = move |text: &str| write_response(text.as_bytes());
+        match response {
+            Ok(chunks) => Ok(Box::new(chunks.inspect(move |chunk| match chunk {
+                Ok(ResponseChunk::Text(text)) => trace(text),
+                // Everything else, including thinking, is written on its own line
+                Ok(chunk) => trace(&format!("\n[{chunk:?}]\n")),
+                Err(e) => trace(&format!("\n[error: {e}]\n")),
+            }))),
+            Err(e) => {
+                trace(&format!("[error: {e}]\n"));
+                Err(e)
+            }
+        }
+    }
+
+    fn request_body(&self, query: Messages) -> Result<String> {
+        self.model.request_body(query)
+    }
+}
+
+type CreateModel<'a> = dyn Fn() -> Result<Box<dyn Queryable + 'a>> + 'a;
+
+/// A model that is only created when it is first queried, so commands that never query it don't load its
+/// configuration or need its credentials. If creating it fails, the next query tries again.
+pub struct Lazy<'a> {
+    create: Box<CreateModel<'a>>,
+    model: OnceCell<Box<dyn Queryable + 'a>>,
+}
+
+impl<'a> Lazy<'a> {
+    pub fn new(create: impl Fn() -> Result<Box<dyn Queryable + 'a>> + 'a) -> Self {
+        Lazy {
+            create: Box::new(create),
+            model: OnceCell::new(),
+        }
+    }
+
+    fn model(&self) -> Result<&(dyn Queryable + 'a)> {
+        if let Some(model) = self.model.get() {
+            return Ok(model.as_ref());
+        }
+        let model = (self.create)()?;
+        Ok(self.model.get_or_init(|| model).as_ref())
+    }
+}
+
+impl Queryable for Lazy<'_> {
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        self.model()?.generate_raw(query, raw)
+    }
+
+    fn request_body(&self, query: Messages) -> Result<String> {
+        self.model()?.request_body(query)
+    }
+}
+
+/// The smallest thinking budget Bedrock accepts.
+pub const MIN_THINKING_BUDGET: u32 = 1024;
+
+/// The region used when none is configured and the AWS environment doesn't set one.
+pub const DEFAULT_REGION: &str = "us-west-2";
+
 pub struct BedrockConfig {
-    pub model_id: &'static str,
-    pub system_prompt: &'static str,
+    pub model_id: String,
+    pub system_prompt: String,
     pub temperature: f32,
     pub top_p: f32,
-    pub region: &'static str,
-    pub aws_profile_name: &'static str,
+    pub max_tokens: i32,
+    /// Sequences that end the response when the model generates them.
+    pub stop_sequences: Vec<String>,
+    /// The AWS region. If `None`, it is taken from the standard AWS environment (`AWS_REGION`,
+    /// `AWS_DEFAULT_REGION` or the profile's region), falling back to `DEFAULT_REGION`.
+    pub region: Option<String>,
+    /// The named AWS profile to load credentials from. If `None`, the default credential provider chain is used
+    /// (environment variables, `AWS_PROFILE`, SSO, instance roles, etc.).
+    pub aws_profile_name: Option<String>,
+    pub max_retries: u32,
+    /// How long to wait for the response to start, and then for each part of it. `None` waits indefinitely.
+    pub timeout: Option<Duration>,
+    /// The tokens the model may spend thinking before it responds, which turns on extended thinking. `None` turns
+    /// it off.
+    pub thinking_budget: Option<u32>,
+}
+
+impl BedrockConfig {
+    /// The request body for a query. With extended thinking, the model only accepts the default temperature, 1, and
+    /// the default top_p, so the configured values aren't sent.
+    fn request_body(&self, query: Messages) -> Result<String> {
+        let thinking = self.thinking_budget.map(|budget_tokens| ReqThinking {
+            thinking_type: "enabled",
+            budget_tokens,
+        });
+        let (temperature, top_p) = match thinking {
+            Some(_) => (1.0, None),
+            None => (
+                query
+                    .temperature
+                    .unwrap_or(self.temperature)
+                    .min(BEDROCK_MAX_TEMPERATURE),
+                Some(self.top_p),
+            ),
+        };
+        Ok(serde_json::to_string(&ReqBody {
+            anthropic_version: "bedrock-2023-05-31",
+            max_tokens: self.max_tokens,
+            temperature,
+            top_p,
+            system: query
+                .system_prompt
+                .as_deref()
+                .unwrap_or(&self.system_prompt),
+            messages: query
+                .messages
+                .into_iter()
+                .map(BedrockMessage::from)
+                .collect(),
+            stop_sequences: &self.stop_sequences,
+            thinking,
+        })?)
+    }
+
+    /// Checks that the region and model id are well-formed, so that a typo is reported before anything is sent.
+    /// `Bedrock::create` then checks that the model is available in the region.
+    pub fn validate(&self) -> Result<()> {
+        let region_pattern = Regex::new(r"^[a-z]{2}(-[a-z]+)+-\d+$").unwrap();
+        if let Some(region) = self.region.as_ref().filter(|r| !region_pattern.is_match(r)) {
+            return Err(format!(
+                "Invalid AWS region {region:?}: expected a region name like {DEFAULT_REGION}"
+            )
+            .into());
+        }
+
+        // A model id is `provider.model` with optional `:`-separated version and context window suffixes and a
+        // cross-region inference prefix (e.g. `us.anthropic.claude-3-5-sonnet-20241022-v2:0` or
+        // `anthropic.claude-instant-v1:2:100k`), or the ARN of a model or inference profile.
+        let model_id = Regex::new(
+            r"^(arn:aws[a-z-]*:bedrock:.+|([a-z]{2,4}\.)?[a-z0-9-]+\.[a-zA-Z0-9.-]+(:\w+)*)$",
+        )
+        .unwrap();
+        if !model_id.is_match(&self.model_id) {
+            return Err(format!(
+                "Invalid Bedrock model id {:?}: expected an id like {}",
+                self.model_id, "anthropic.claude-3-5-sonnet-20241022-v2:0"
+            )
+            .into());
+        }
+
+        // The budget counts towards the response's tokens, so it must leave room for the response itself
+        if let Some(budget) = self.thinking_budget {
+            if budget < MIN_THINKING_BUDGET || i64::from(budget) >= i64::from(self.max_tokens) {
+                return Err(format!(
+                    "Invalid thinking budget {budget}: it must be at least {MIN_THINKING_BUDGET} and less than \
+                     max_tokens ({})",
+                    self.max_tokens
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Bedrock implementation of Queryable.
 /// The aws client uses async/tokio, and so the associated runtime is for use (`block_on`) with the client.
-///
+/// It is a single-threaded runtime: requests are made one at a time, and each `block_on` then runs on the calling
+/// thread, rather than handing work to and waking a pool of worker threads for every streamed chunk.
 pub struct Bedrock {
     pub model_config: BedrockConfig,
     pub runtime: Runtime,
     pub client: Client,
+    /// The region requests are sent to, once resolved.
+    pub region: String,
 }
 
 impl Bedrock {
-    pub fn create(model_config: BedrockConfig) -> Result<Self> {
-        let runtime = Runtime::new()?;
+    pub fn create(mut model_config: BedrockConfig) -> Result<Self> {
+        model_config.validate()?;
+        // Bedrock only accepts up to 1, so a higher temperature, e.g. one meant for another backend, is lowered
+        model_config.temperature = clamp_sampling_parameter(
+            "temperature",
+            model_config.temperature,
+            BEDROCK_MAX_TEMPERATURE,
+        );
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
         let start = Instant::now();
-        let config = runtime.block_on(
-            aws_config::from_env()
-                .region(model_config.region)
-                .profile_name(model_config.aws_profile_name)
-                .load(),
+        // Only the region and profile configured for claippy override the standard AWS environment
+        let region = match &model_config.region {
+            Some(region) => RegionProviderChain::first_try(Region::new(region.clone())),
+            None => {
+                let mut default_region = DefaultRegionChain::builder();
+                if let Some(profile_name) = &model_config.aws_profile_name {
+                    default_region = default_region.profile_name(profile_name);
+                }
+                RegionProviderChain::first_try(default_region.build())
+            }
+        }
+        .or_else(Region::from_static(DEFAULT_REGION));
+        let mut loader = aws_config::from_env()
+            .region(region)
+            // Retries are handled in `generate`, so that we control which errors are retried
+            .retry_config(RetryConfig::disabled());
+        if let Some(profile_name) = &model_config.aws_profile_name {
+            loader = loader.profile_name(profile_name);
+        }
+        let config = runtime.block_on(loader.load());
+        let region = config
+            .region()
+            .map_or(DEFAULT_REGION.to_owned(), |region| region.to_string());
+        log::info!(
+            "Load aws cfg: {:?}ms, region {region}",
+            (Instant::now() - start).as_millis()
         );
-        log::info!("Load aws cfg: {:?}ms", (Instant::now() - start).as_millis());
         let client = aws_sdk_bedrockruntime::Client::new(&config);
-        Ok(Bedrock {
+        let bedrock = Bedrock {
             model_config,
             runtime,
             client,
-        })
+            region,
+        };
+        bedrock.check_model_available(&aws_sdk_bedrock::Client::new(&config))?;
+        Ok(bedrock)
+    }
+
+    /// Checks that the model is listed in the region, so that a model that isn't there is reported before anything is
+    /// sent. The check needs `bedrock:GetFoundationModel`, or `bedrock:GetInferenceProfile` for a cross-region
+    /// inference profile. If it can't be made, e.g. without those permissions, the request is sent anyway and reports
+    /// any problem itself.
+    fn check_model_available(&self, control: &aws_sdk_bedrock::Client) -> Result<()> {
+        let model_id = &self.model_config.model_id;
+        let is_inference_profile = model_id.contains(":inference-profile/")
+            || model_id
+                .split_once('.')
+                .is_some_and(|(prefix, _)| CROSS_REGION_PREFIXES.contains(&prefix));
+        if model_id.starts_with("arn:")
+            && !is_inference_profile
+            && !model_id.contains(":foundation-model/")
+        {
+            // Custom, imported and provisioned models are only known to the runtime
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let check = async {
+            if is_inference_profile {
+                let request = control
+                    .get_inference_profile()
+                    .inference_profile_identifier(model_id);
+                request.send().await.map(|_| ()).map_err(model_check_error)
+            } else {
+                let request = control.get_foundation_model().model_identifier(model_id);
+                request.send().await.map(|_| ()).map_err(model_check_error)
+            }
+        };
+        let result = self.runtime.block_on(async {
+            match self.model_config.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, check)
+                    .await
+                    .unwrap_or_else(|_| Err(ModelCheckError::Other("timed out".to_owned()))),
+                None => check.await,
+            }
+        });
+        log::info!("Check model: {:?}ms", start.elapsed().as_millis());
+        match result {
+            Ok(()) => Ok(()),
+            Err(ModelCheckError::NotListed(message)) => Err(format!(
+                "Model {model_id} is not available in {}: {message}\n\
+                Check the model id; `aws bedrock list-foundation-models --region {}` lists the models there, and \
+                `aws bedrock list-inference-profiles` the cross-region inference profiles.",
+                self.region, self.region
+            )
+            .into()),
+            Err(ModelCheckError::Other(e)) => {
+                log::warn!("Could not check that model {model_id} is available: {e}");
+                Ok(())
+            }
+        }
+    }
+}
+
+// The prefixes of cross-region inference profile ids, e.g. `us.anthropic.claude-3-5-sonnet-20241022-v2:0`.
+const CROSS_REGION_PREFIXES: [&str; 7] = ["us", "us-gov", "eu", "apac", "jp", "au", "global"];
+
+/// Why the model's availability couldn't be confirmed: either it isn't listed, or the check itself failed.
+enum ModelCheckError {
+    NotListed(String),
+    Other(String),
+}
+
+fn model_check_error<E, R>(error: SdkError<E, R>) -> ModelCheckError
+where
+    E: ProvideErrorMetadata + Error + 'static,
+    R: Debug,
+{
+    let Some(service_error) = error.as_service_error() else {
+        return ModelCheckError::Other(DisplayErrorContext(&error).to_string());
+    };
+    let message = service_error
+        .message()
+        .unwrap_or("unknown error")
+        .to_owned();
+    match service_error.code() {
+        Some("ResourceNotFoundException" | "ValidationException") => {
+            ModelCheckError::NotListed(message)
+        }
+        code => ModelCheckError::Other(format!("{}: {message}", code.unwrap_or("error"))),
     }
 }
 
 impl Queryable for Bedrock {
-    fn generate(&self, query: Messages) -> ResultIterator<Result<String>> {
-        let body_str = serde_json::to_string(&ReqBody {
-            anthropic_version: "bedrock-2023-05-31",
-            max_tokens: 4096, // the maximum
-            temperature: self.model_config.temperature,
-            top_p: self.model_config.top_p,
-            system: self.model_config.system_prompt,
-            messages: query.messages,
-        })?;
+    fn request_body(&self, query: Messages) -> Result<String> {
+        self.model_config.request_body(query)
+    }
+
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        mut raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        let body_str = self.request_body(query)?;
 
         log::info!("Request Body: {body_str:?}");
 
         let body = body_str.into_bytes();
 
-        let async_request = self
-            .client
-            .invoke_model_with_response_stream()
-            .model_id(self.model_config.model_id)
-            .body(Blob::new(body))
-            .send();
-
         log::info!("Starting request:");
-        let response = self.runtime.block_on(async_request)?;
+        let response = self.runtime.block_on(async {
+            let mut attempt = 0;
+            loop {
+                let request = self
+                    .client
+                    .invoke_model_with_response_stream()
+                    .model_id(&self.model_config.model_id)
+                    .body(Blob::new(body.clone()))
+                    .send();
+                let result = with_timeout(
+                    self.model_config.timeout,
+                    "Bedrock did not start responding within",
+                    request,
+                )
+                .await?;
+                match result {
+                    Err(e) if is_credentials_error(&e) => {
+                        break Err(self.credentials_help(&e).into());
+                    }
+                    Err(SdkError::ServiceError(context)) if is_model_unavailable(context.err()) => {
+                        break Err(self.model_unavailable_help(context.err()).into());
+                    }
+                    Err(e) if attempt < self.model_config.max_retries && is_retryable(&e) => {
+                        attempt += 1;
+                        let backoff = retry_backoff(attempt);
+                        log::warn!("Request failed ({e:?}), retry {attempt} in {backoff:?}");
+                        tokio::time::sleep(backoff).await;
+                    }
+                    result => break result.map_err(Box::<dyn Error>::from),
+                }
+            }
+        })?;
         log::info!("Response: {:?}", response.content_type);
         let mut event_receiver = response.body;
+        let mut frames = JsonFrames::default();
         let iter = std::iter::from_fn(move || {
-            convert_to_option(self.runtime.block_on(event_receiver.recv()))
+            let received = with_timeout(
+                self.model_config.timeout,
+                "Bedrock sent nothing more for",
+                event_receiver.recv(),
+            );
+            match self.runtime.block_on(received) {
+                Ok(received) => convert_to_option(received),
+                Err(e) => Some(Err(e)),
+            }
         })
-        .map(|item| item.and_then(parse_claude_api_text))
-        .filter_map(|result| match result {
-            Ok(None) => None,
-            Ok(Some(string)) => Some(Ok(string)),
-            Err(e) => Some(Err(e)),
+        .flat_map(move |item| match item {
+            Ok(bytes) => {
+                raw(&bytes);
+                frames
+                    .push(&bytes)
+                    .into_iter()
+                    .filter_map(parse_claude_api_text)
+                    .map(Ok)
+                    .collect()
+            }
+            Err(e) => vec![Err(e)],
         });
 
         Ok(Box::new(iter))
     }
 }
 
+impl Bedrock {
+    /// A message for a request that failed for lack of valid credentials, saying how to set them up.
+    fn credentials_help<R: Debug + 'static>(
+        &self,
+        error: &SdkError<InvokeModelWithResponseStreamError, R>,
+    ) -> String {
+        let credentials = match &self.model_config.aws_profile_name {
+            Some(profile_name) => format!("the AWS profile {profile_name}"),
+            None => "the default AWS credential chain".to_owned(),
+        };
+        format!(
+            "Could not authenticate with Bedrock using {credentials}: {}\n\
+            Set up credentials with `aws configure`, log in with `aws sso login` if you use SSO, or set \
+            AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY. Set CLAIPPY_AWS_PROFILE to use a named profile.",
+            credentials_error_summary(error)
+        )
+    }
+
+    /// A message for a request that failed because the model can't be used in the configured region.
+    fn model_unavailable_help(&self, error: &InvokeModelWithResponseStreamError) -> String {
+        format!(
+            "Model {} is not available in {}: {}\n\
+            Check the model id, and that access to the model is enabled in the Bedrock console for this region.",
+            self.model_config.model_id,
+            self.region,
+            error.message().unwrap_or("unknown error")
+        )
+    }
+}
+
+// The model id doesn't exist, or the account hasn't been granted access to it in this region.
+fn is_model_unavailable(error: &InvokeModelWithResponseStreamError) -> bool {
+    let message = error.message().unwrap_or_default().to_lowercase();
+    error.is_resource_not_found_exception()
+        || (error.is_access_denied_exception() && message.contains("model"))
+        || (error.is_validation_exception() && message.contains("model identifier"))
+}
+
+// Service error codes for credentials that were loaded but rejected.
+const REJECTED_CREDENTIALS_CODES: [&str; 4] = [
+    "ExpiredTokenException",
+    "UnrecognizedClientException",
+    "InvalidSignatureException",
+    "InvalidClientTokenId",
+];
+
+// Missing credentials are reported as a dispatch failure, several sources deep, so the whole chain is checked.
+fn is_credentials_error<R: Debug + 'static>(
+    error: &SdkError<InvokeModelWithResponseStreamError, R>,
+) -> bool {
+    if let SdkError::ServiceError(context) = error {
+        return context
+            .err()
+            .code()
+            .is_some_and(|code| REJECTED_CREDENTIALS_CODES.contains(&code));
+    }
+    find_credentials_error(error).is_some()
+}
+
+fn find_credentials_error<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a CredentialsError> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(credentials_error) = error.downcast_ref::<CredentialsError>() {
+            return Some(credentials_error);
+        }
+        source = error.source();
+    }
+    None
+}
+
+// The SDK's debug output for a credentials error lists every provider it tried, so just say what went wrong.
+fn credentials_error_summary<R: Debug + 'static>(
+    error: &SdkError<InvokeModelWithResponseStreamError, R>,
+) -> String {
+    match find_credentials_error(error) {
+        Some(CredentialsError::CredentialsNotLoaded(_)) => "no credentials were found".to_owned(),
+        Some(CredentialsError::ProviderTimedOut(_)) => "timed out loading credentials".to_owned(),
+        Some(e) => e.to_string(),
+        None => DisplayErrorContext(error).to_string(),
+    }
+}
+
+// Throttling and transient service errors are worth retrying; anything else (e.g. validation) will fail again.
+fn is_retryable<R>(error: &SdkError<InvokeModelWithResponseStreamError, R>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        SdkError::ServiceError(context) => {
+            let error = context.err();
+            error.is_throttling_exception()
+                || error.is_service_unavailable_exception()
+                || error.is_internal_server_exception()
+                || error.is_model_timeout_exception()
+                || error.is_model_not_ready_exception()
+        }
+        _ => false,
+    }
+}
+
+/// Waits for `future`, failing if `timeout` passes first with `description`, which is followed by the timeout.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    description: &str,
+    future: impl Future<Output = T>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return Ok(future.await);
+    };
+    tokio::time::timeout(timeout, future).await.map_err(|_| {
+        format!(
+            "{description} {} seconds. Set CLAIPPY_TIMEOUT_SECS to wait longer, or 0 to wait indefinitely",
+            timeout.as_secs()
+        )
+        .into()
+    })
+}
+
+/// Exponential backoff for the given retry attempt (starting at 1), up to `MAX_RETRY_BACKOFF`, with +/-50% jitter.
+fn retry_backoff(attempt: u32) -> Duration {
+    let factor = 2u32
+        .checked_pow(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    let base = INITIAL_RETRY_BACKOFF
+        .saturating_mul(factor)
+        .min(MAX_RETRY_BACKOFF);
+    // Not a real random source, but enough to spread out concurrent clients.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    base.mul_f64(0.5 + f64::from(nanos % 1000) / 1000.0)
+}
+
 #[derive(Deserialize)]
 struct RspText {
     text: Option<String>,
+    thinking: Option<String>,
+    stop_reason: Option<String>,
+    stop_sequence: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RspInvocationMetrics {
+    input_token_count: u64,
+    output_token_count: u64,
 }
 
 #[derive(Deserialize)]
 struct RspChunk {
     r#type: String,
     delta: Option<RspText>,
+    #[serde(rename = "amazon-bedrock-invocationMetrics")]
+    invocation_metrics: Option<RspInvocationMetrics>,
 }
 
-// Parse the response chunks and extract the text. Ensure we don't fail on parsing, but discard chunks that don't have text
+/// Frames the JSON objects in a response's payload parts, since an object may be split across parts (even within
+/// a multi-byte character), or one part may hold several objects.
+#[derive(Default)]
+struct JsonFrames {
+    pending: Vec<u8>,
+}
+
+impl JsonFrames {
+    /// Adds a payload part, returning the objects it completes. Malformed JSON, and objects that aren't a
+    /// `RspChunk`, are logged and skipped, so that one bad part doesn't end the stream.
+    fn push(&mut self, bytes: &[u8]) -> Vec<RspChunk> {
+        self.pending.extend_from_slice(bytes);
+        let mut chunks = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let rest = &self.pending[consumed..];
+            let mut values = serde_json::Deserializer::from_slice(rest).into_iter::<Value>();
+            match values.next() {
+                Some(Ok(value)) => {
+                    consumed += values.byte_offset();
+                    match serde_json::from_value(value) {
+                        Ok(chunk) => chunks.push(chunk),
+                        Err(e) => log::warn!("Skipping unexpected response chunk: {e}"),
+                    }
+                }
+                // The rest of the object is in a later part
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    let malformed = malformed_len(rest);
+                    log::warn!(
+                        "Skipping malformed response chunk {:?}: {e}",
+                        String::from_utf8_lossy(&rest[..malformed])
+                    );
+                    consumed += malformed;
+                }
+                None => {
+                    consumed = self.pending.len();
+                    break;
+                }
+            }
+        }
+        self.pending.drain(..consumed);
+        chunks
+    }
+}
+
+// How much of `bytes` to skip past a malformed object at its start, so that parsing resumes with the next object:
+// up to the object's closing brace, or to the next opening brace if it doesn't start with one.
+fn malformed_len(bytes: &[u8]) -> usize {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    if bytes.get(start) != Some(&b'{') {
+        return bytes[start..]
+            .iter()
+            .position(|b| *b == b'{')
+            .map_or(bytes.len(), |offset| start + offset);
+    }
+
+    let (mut depth, mut in_string, mut escaped) = (0, false, false);
+    for (i, b) in bytes.iter().enumerate().skip(start) {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    bytes.len()
+}
+
+// Extract the text, stop reason and token usage from the response chunks, discarding chunks that have none of them.
 /// e.g.s:
 /// Ok("{\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}")
+/// Ok("{\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"thinking_delta\",\"thinking\":\"The user\"}}")
 /// Ok("{\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}")
 /// Ok("{\"type\":\"content_block_stop\",\"index\":0}")
 /// Ok("{\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\",\"stop_sequence\":null},\"usage\":{\"output_tokens\":12}}")
 /// Ok("{\"type\":\"message_stop\",\"amazon-bedrock-invocationMetrics\":{ ... }})
-fn parse_claude_api_text(chunk_text: String) -> Result<Option<String>> {
-    // This copies the part of the response chunks that we want to extract. Can we avoid this copy?
-
-    log::debug!("Input: {chunk_text:?}");
-
-    match serde_json::from_str(&chunk_text)? {
+fn parse_claude_api_text(chunk: RspChunk) -> Option<ResponseChunk> {
+    match chunk {
         RspChunk {
             r#type,
-            delta: Some(RspText { text: Some(text) }),
-        } if r#type == "content_block_delta" => Ok(Some(text)),
-        _ => Ok(None),
+            delta: Some(RspText {
+                text: Some(text), ..
+            }),
+            ..
+        } if r#type == "content_block_delta" => Some(ResponseChunk::Text(text)),
+        RspChunk {
+            r#type,
+            delta:
+                Some(RspText {
+                    thinking: Some(thinking),
+                    ..
+                }),
+            ..
+        } if r#type == "content_block_delta" => Some(ResponseChunk::Thinking(thinking)),
+        RspChunk {
+            r#type,
+            delta:
+                Some(RspText {
+                    stop_reason: Some(reason),
+                    stop_sequence,
+                    ..
+                }),
+            ..
+        } if r#type == "message_delta" => Some(ResponseChunk::Stop(match reason.as_str() {
+            "max_tokens" => StopReason::MaxTokens,
+            "stop_sequence" => StopReason::StopSequence(stop_sequence),
+            _ => StopReason::Other(reason),
+        })),
+        RspChunk {
+            r#type,
+            invocation_metrics: Some(metrics),
+            ..
+        } if r#type == "message_stop" => Some(ResponseChunk::Usage(TokenUsage {
+            input_tokens: metrics.input_token_count,
+            output_tokens: metrics.output_token_count,
+        })),
+        _ => None,
     }
 }
 
 fn convert_to_option<T>(
     recv: core::result::Result<Option<ResponseStream>, SdkError<ResponseStreamError, T>>,
-) -> Option<Result<String>>
+) -> Option<Result<Vec<u8>>>
 where
     T: Send + Sync + Debug + 'static,
 {
@@ -146,10 +967,621 @@ where
         Err(e) => Some(Err(Box::new(e.into_service_error()))),
         Ok(Some(ResponseStream::Chunk(PayloadPart {
             bytes: Some(bytes), ..
-        }))) => {
-            Some(String::from_utf8(bytes.into_inner()).map_err(|e| Box::new(e) as Box<dyn Error>))
-        }
-        Ok(Some(_)) => Some(Ok(String::new())), //ResponseStream::Unknown
+        }))) => Some(Ok(bytes.into_inner())),
+        Ok(Some(_)) => Some(Ok(Vec::new())), //ResponseStream::Unknown
         Ok(None) => None,
     }
 }
+
+pub struct OpenAiConfig {
+    pub model: String,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub system_prompt: String,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub stop_sequences: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OpenAiReqBody<'a> {
+    model: &'a str,
+    messages: Vec<OpenAiMessage>,
+    temperature: f32,
+    top_p: f32,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+    stream: bool,
+    stream_options: OpenAiStreamOptions,
+}
+
+#[derive(Serialize)]
+struct OpenAiStreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Serialize)]
+struct OpenAiMessage {
+    role: String,
+    content: OpenAiContent,
+}
+
+/// A message's content: plain text, or content parts when it has images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OpenAiContent {
+    Text(String),
+    Parts(Vec<OpenAiContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OpenAiContentPart {
+    ImageUrl { image_url: OpenAiImageUrl },
+    Text { text: String },
+}
+
+#[derive(Serialize)]
+struct OpenAiImageUrl {
+    url: String,
+}
+
+impl From<Message> for OpenAiMessage {
+    fn from(message: Message) -> Self {
+        let content = if message.images.is_empty() {
+            OpenAiContent::Text(message.content)
+        } else {
+            let mut parts: Vec<OpenAiContentPart> = message
+                .images
+                .into_iter()
+                .map(|image| OpenAiContentPart::ImageUrl {
+                    image_url: OpenAiImageUrl {
+                        url: format!("data:{};base64,{}", image.media_type, image.data),
+                    },
+                })
+                .collect();
+            parts.push(OpenAiContentPart::Text {
+                text: message.content,
+            });
+            OpenAiContent::Parts(parts)
+        };
+        OpenAiMessage {
+            role: message.role,
+            content,
+        }
+    }
+}
+
+/// Implementation of Queryable for the OpenAI chat completions API, or any server compatible
+/// with it (e.g. llama.cpp or vLLM) via a different `base_url`.
+pub struct OpenAiChat {
+    pub model_config: OpenAiConfig,
+    pub client: reqwest::blocking::Client,
+}
+
+impl OpenAiChat {
+    pub fn create(model_config: OpenAiConfig) -> Result<Self> {
+        // Responses are streamed for as long as the model generates, so don't apply the default request timeout
+        let client = reqwest::blocking::Client::builder()
+            .timeout(None::<Duration>)
+            .build()?;
+        Ok(OpenAiChat {
+            model_config,
+            client,
+        })
+    }
+}
+
+impl Queryable for OpenAiChat {
+    fn request_body(&self, query: Messages) -> Result<String> {
+        let mut messages = Vec::with_capacity(query.messages.len() + 1);
+        messages.push(OpenAiMessage {
+            role: "system".to_owned(),
+            content: OpenAiContent::Text(
+                query
+                    .system_prompt
+                    .unwrap_or_else(|| self.model_config.system_prompt.clone()),
+            ),
+        });
+        messages.extend(query.messages.into_iter().map(OpenAiMessage::from));
+
+        Ok(serde_json::to_string(&OpenAiReqBody {
+            model: &self.model_config.model,
+            messages,
+            temperature: query.temperature.unwrap_or(self.model_config.temperature),
+            top_p: self.model_config.top_p,
+            stop: &self.model_config.stop_sequences,
+            stream: true,
+            stream_options: OpenAiStreamOptions {
+                include_usage: true,
+            },
+        })?)
+    }
+
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        mut raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        let body_str = self.request_body(query)?;
+
+        log::info!("Request Body: {body_str:?}");
+
+        let url = format!(
+            "{}/chat/completions",
+            self.model_config.base_url.trim_end_matches('/')
+        );
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body_str);
+        if let Some(api_key) = &self.model_config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        log::info!("Starting request:");
+        let response = request.send()?;
+        log::info!("Response: {:?}", response.status());
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("OpenAI request failed ({status}): {}", response.text()?).into());
+        }
+
+        let iter = BufReader::new(response)
+            .lines()
+            .inspect(move |line| {
+                if let Ok(line) = line {
+                    raw(format!("{line}\n").as_bytes());
+                }
+            })
+            .map(|line| line.map_err(Into::into).and_then(parse_openai_sse_line))
+            .filter_map(Result::transpose);
+
+        Ok(Box::new(iter))
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiRspDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiRspChoice {
+    delta: OpenAiRspDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiRspUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct OpenAiRspChunk {
+    #[serde(default)]
+    choices: Vec<OpenAiRspChoice>,
+    usage: Option<OpenAiRspUsage>,
+}
+
+/// Parse a single line of the server-sent event stream. Only `data:` lines carry chunks, e.g.:
+/// data: {"choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}], ...}
+/// data: {"choices":[{"index":0,"delta":{},"finish_reason":"stop"}], ...}
+/// data: {"choices":[],"usage":{"prompt_tokens":9,"completion_tokens":12,"total_tokens":21}, ...}
+/// data: [DONE]
+fn parse_openai_sse_line(line: String) -> Result<Option<ResponseChunk>> {
+    log::debug!("Input: {line:?}");
+
+    let data = match line.strip_prefix("data:").map(str::trim) {
+        Some("[DONE]") | None => return Ok(None),
+        Some(data) => data,
+    };
+
+    let chunk: OpenAiRspChunk = serde_json::from_str(data)?;
+    if let Some(choice) = chunk.choices.into_iter().next() {
+        if let Some(content) = choice.delta.content.filter(|content| !content.is_empty()) {
+            return Ok(Some(ResponseChunk::Text(content)));
+        }
+        if let Some(reason) = choice.finish_reason {
+            return Ok(Some(ResponseChunk::Stop(match reason.as_str() {
+                "length" => StopReason::MaxTokens,
+                _ => StopReason::Other(reason),
+            })));
+        }
+    }
+
+    Ok(chunk.usage.map(|usage| {
+        ResponseChunk::Usage(TokenUsage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        })
+    }))
+}
+
+pub struct OllamaConfig {
+    pub model: String,
+    pub host: String,
+    pub system_prompt: String,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub stop_sequences: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OllamaReqBody<'a> {
+    model: &'a str,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: OllamaOptions<'a>,
+}
+
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    /// Base64-encoded images, for multimodal models.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl From<Message> for OllamaMessage {
+    fn from(message: Message) -> Self {
+        OllamaMessage {
+            role: message.role,
+            content: message.content,
+            images: message.images.into_iter().map(|image| image.data).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OllamaOptions<'a> {
+    temperature: f32,
+    top_p: f32,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+}
+
+/// Implementation of Queryable for a local Ollama server. This needs no async runtime, so it uses the blocking
+/// reqwest client.
+pub struct Ollama {
+    pub model_config: OllamaConfig,
+    pub client: reqwest::blocking::Client,
+}
+
+impl Ollama {
+    pub fn create(model_config: OllamaConfig) -> Result<Self> {
+        // Responses are streamed for as long as the model generates, so don't apply the default request timeout
+        let client = reqwest::blocking::Client::builder()
+            .timeout(None::<Duration>)
+            .build()?;
+        Ok(Ollama {
+            model_config,
+            client,
+        })
+    }
+}
+
+impl Queryable for Ollama {
+    fn request_body(&self, query: Messages) -> Result<String> {
+        let mut messages = Vec::with_capacity(query.messages.len() + 1);
+        messages.push(OllamaMessage {
+            role: "system".to_owned(),
+            content: query
+                .system_prompt
+                .unwrap_or_else(|| self.model_config.system_prompt.clone()),
+            images: Vec::new(),
+        });
+        messages.extend(query.messages.into_iter().map(OllamaMessage::from));
+
+        Ok(serde_json::to_string(&OllamaReqBody {
+            model: &self.model_config.model,
+            messages,
+            stream: true,
+            options: OllamaOptions {
+                temperature: query.temperature.unwrap_or(self.model_config.temperature),
+                top_p: self.model_config.top_p,
+                stop: &self.model_config.stop_sequences,
+            },
+        })?)
+    }
+
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        mut raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        let body_str = self.request_body(query)?;
+
+        log::info!("Request Body: {body_str:?}");
+
+        // OLLAMA_HOST is commonly given without a scheme, e.g. `127.0.0.1:11434`
+        let host = self.model_config.host.trim_end_matches('/');
+        let url = if host.starts_with("http://") || host.starts_with("https://") {
+            format!("{host}/api/chat")
+        } else {
+            format!("http://{host}/api/chat")
+        };
+
+        log::info!("Starting request:");
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body_str)
+            .send()?;
+        log::info!("Response: {:?}", response.status());
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("Ollama request failed ({status}): {}", response.text()?).into());
+        }
+
+        let iter = BufReader::new(response)
+            .lines()
+            .inspect(move |line| {
+                if let Ok(line) = line {
+                    raw(format!("{line}\n").as_bytes());
+                }
+            })
+            .map(|line| line.map_err(Into::into).and_then(parse_ollama_line))
+            .filter_map(Result::transpose);
+
+        Ok(Box::new(iter))
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaRspMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaRspChunk {
+    message: Option<OllamaRspMessage>,
+    #[serde(default)]
+    done: bool,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+/// Parse a single line of the newline-delimited JSON stream, e.g.:
+/// {"model":"llama3.1","message":{"role":"assistant","content":"Hello"},"done":false}
+/// {"model":"llama3.1","message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":26,"eval_count":290, ...}
+fn parse_ollama_line(line: String) -> Result<Option<ResponseChunk>> {
+    log::debug!("Input: {line:?}");
+
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match serde_json::from_str(&line)? {
+        OllamaRspChunk {
+            done: true,
+            prompt_eval_count,
+            eval_count,
+            ..
+        } => Ok(Some(ResponseChunk::Usage(TokenUsage {
+            input_tokens: prompt_eval_count.unwrap_or_default(),
+            output_tokens: eval_count.unwrap_or_default(),
+        }))),
+        OllamaRspChunk {
+            message: Some(OllamaRspMessage { content }),
+            ..
+        } if !content.is_empty() => Ok(Some(ResponseChunk::Text(content))),
+        _ => Ok(None),
+    }
+}
+
+/// A model that replies with canned responses, for testing commands without a backend.
+/// Each query takes the next queued response, yielding its chunks in order. An `Err` chunk is yielded as an error
+/// mid-stream.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Default)]
+pub struct MockQueryable {
+    responses: std::cell::RefCell<
+        std::collections::VecDeque<Vec<std::result::Result<ResponseChunk, String>>>,
+    >,
+    queries: std::cell::RefCell<Vec<Messages>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockQueryable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a response made of the given chunks.
+    pub fn respond_with(self, chunks: Vec<std::result::Result<ResponseChunk, String>>) -> Self {
+        self.responses.borrow_mut().push_back(chunks);
+        self
+    }
+
+    /// Queues a response of the given text, in a single chunk.
+    pub fn respond_with_text(self, text: &str) -> Self {
+        self.respond_with(vec![Ok(ResponseChunk::Text(text.to_owned()))])
+    }
+
+    /// The queries received so far, oldest first, which are cleared.
+    pub fn take_queries(&self) -> Vec<Messages> {
+        std::mem::take(&mut self.queries.borrow_mut())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Queryable for MockQueryable {
+    fn generate_raw<'a>(
+        &'a self,
+        query: Messages,
+        _raw: RawSink<'a>,
+    ) -> ResultIterator<'a, Result<ResponseChunk>> {
+        self.queries.borrow_mut().push(query);
+        let chunks = self
+            .responses
+            .borrow_mut()
+            .pop_front()
+            .ok_or("MockQueryable has no more responses")?;
+        Ok(Box::new(
+            chunks.into_iter().map(|chunk| chunk.map_err(Into::into)),
+        ))
+    }
+
+    fn request_body(&self, query: Messages) -> Result<String> {
+        Ok(serde_json::to_string(&query)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(text: &str) -> String {
+        serde_json::json!({
+            "type": "content_block_delta",
+            "index": 0,
+            "delta": { "type": "text_delta", "text": text }
+        })
+        .to_string()
+    }
+
+    fn texts(chunks: Vec<RspChunk>) -> Vec<String> {
+        chunks
+            .into_iter()
+            .filter_map(|chunk| chunk.delta?.text)
+            .collect()
+    }
+
+    #[test]
+    fn frames_object_split_across_parts() {
+        let object = delta("Hello");
+        let (first, second) = object.split_at(object.len() / 2);
+        let mut frames = JsonFrames::default();
+        assert!(frames.push(first.as_bytes()).is_empty());
+        assert_eq!(texts(frames.push(second.as_bytes())), ["Hello"]);
+        assert!(frames.pending.is_empty());
+    }
+
+    #[test]
+    fn frames_object_split_within_character() {
+        let object = delta("café");
+        let bytes = object.as_bytes();
+        // Within the two bytes of the é
+        let split = object.find('é').unwrap() + 1;
+        let mut frames = JsonFrames::default();
+        assert!(frames.push(&bytes[..split]).is_empty());
+        assert_eq!(texts(frames.push(&bytes[split..])), ["café"]);
+    }
+
+    #[test]
+    fn frames_several_objects_in_one_part() {
+        let part = format!("{}{}", delta("Hello"), delta(" world"));
+        let mut frames = JsonFrames::default();
+        assert_eq!(texts(frames.push(part.as_bytes())), ["Hello", " world"]);
+    }
+
+    #[test]
+    fn skips_only_malformed_object() {
+        let part = format!(
+            r#"{{"type": "content_block_delta", "delta": }}{}"#,
+            delta("Hello")
+        );
+        let mut frames = JsonFrames::default();
+        assert_eq!(texts(frames.push(part.as_bytes())), ["Hello"]);
+        assert!(frames.pending.is_empty());
+    }
+
+    fn bedrock_config(model_id: &str) -> BedrockConfig {
+        BedrockConfig {
+            model_id: model_id.to_owned(),
+            system_prompt: String::new(),
+            temperature: 1.0,
+            top_p: 1.0,
+            max_tokens: 100,
+            stop_sequences: Vec::new(),
+            region: None,
+            aws_profile_name: None,
+            max_retries: 0,
+            timeout: None,
+            thinking_budget: None,
+        }
+    }
+
+    #[test]
+    fn bedrock_model_ids_may_have_several_suffixes() {
+        for model_id in [
+            "anthropic.claude-3-5-sonnet-20241022-v2:0",
+            "us.anthropic.claude-3-5-sonnet-20241022-v2:0",
+            "anthropic.claude-instant-v1:2:100k",
+            "anthropic.claude-3-haiku-20240307-v1:0:48k",
+            "meta.llama3-8b-instruct-v1",
+        ] {
+            assert!(bedrock_config(model_id).validate().is_ok(), "{model_id}");
+        }
+        for model_id in ["claude", "anthropic.claude-v2:", "anthropic.claude-v2::0"] {
+            assert!(bedrock_config(model_id).validate().is_err(), "{model_id}");
+        }
+    }
+
+    #[test]
+    fn retry_backoff_is_capped_for_any_attempt() {
+        assert!(retry_backoff(1) <= INITIAL_RETRY_BACKOFF * 3 / 2);
+        for attempt in [10, 32, 33, 64, u32::MAX] {
+            assert!(
+                retry_backoff(attempt) <= MAX_RETRY_BACKOFF * 3 / 2,
+                "{attempt}"
+            );
+        }
+    }
+
+    #[test]
+    fn bedrock_request_enables_thinking_with_a_budget() {
+        let query = || Messages::new(vec![Message::user("Hi".to_owned())]);
+        let body = |config: &BedrockConfig| -> serde_json::Value {
+            serde_json::from_str(&config.request_body(query()).unwrap()).unwrap()
+        };
+
+        let mut config = bedrock_config("anthropic.claude-3-7-sonnet-20250219-v1:0");
+        config.temperature = 0.2;
+        config.max_tokens = 8000;
+        let without = body(&config);
+        assert!(without.get("thinking").is_none());
+        assert!((without["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+
+        config.thinking_budget = Some(2000);
+        assert!(config.validate().is_ok());
+        let with = body(&config);
+        assert_eq!(
+            with["thinking"],
+            serde_json::json!({"type": "enabled", "budget_tokens": 2000})
+        );
+        assert_eq!(with["temperature"], 1.0);
+        assert!(with.get("top_p").is_none());
+
+        for budget in [MIN_THINKING_BUDGET - 1, 8000] {
+            config.thinking_budget = Some(budget);
+            assert!(config.validate().is_err(), "{budget}");
+        }
+    }
+
+    #[test]
+    fn only_bedrock_lowers_temperatures_above_1() {
+        assert_eq!(
+            clamp_sampling_parameter("temperature", 1.5, MAX_TEMPERATURE),
+            1.5
+        );
+        assert_eq!(
+            clamp_sampling_parameter("temperature", 2.5, MAX_TEMPERATURE),
+            2.0
+        );
+        assert_eq!(clamp_sampling_parameter("top_p", 1.5, MAX_TOP_P), 1.0);
+
+        let mut query = Messages::new(vec![Message::user("Hi".to_owned())]);
+        query.temperature = Some(1.5);
+        let body = bedrock_config("anthropic.claude-v2")
+            .request_body(query)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["temperature"], 1.0);
+    }
+}
@@ -0,0 +1,1078 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::mem;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Style, Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use termimad::crossterm::style::Stylize;
+use termimad::{terminal_size, MadSkin};
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    lang::extension_for_language,
+    model::{Conversation, MessageParts, Result, PART_SEPARATOR},
+};
+
+/// Controls how output is rendered to the terminal.
+#[derive(Debug)]
+pub struct RenderOptions {
+    /// Whether to style output: colors, markdown styling and syntax highlighting.
+    pub color: bool,
+    /// Whether stdout is a terminal, so responses can be streamed and then repainted in place.
+    pub interactive: bool,
+    /// Whether query responses are emitted as a single JSON object instead of being streamed as rendered text.
+    pub json: bool,
+    /// Whether responses are printed exactly as received, without markdown formatting, reflowing or highlighting.
+    pub raw: bool,
+    /// The syntax highlighting theme: one of syntect's default themes or a path to a `.tmTheme` file.
+    pub theme: Option<String>,
+    /// Whether to show the model's reasoning, for models that think before responding.
+    pub thinking: bool,
+    pub artifacts: ArtifactLayout,
+    /// The width that markdown is wrapped to, and that code backgrounds extend to.
+    pub width: u16,
+}
+
+/// What is printed around each line of an artifact's content.
+#[derive(Debug, Clone, Copy)]
+pub struct ArtifactLayout {
+    /// Whether each artifact is introduced by a line with its identifier and language. Without it, an artifact
+    /// selected in the terminal copies as just its content.
+    pub headers: bool,
+    /// Whether each line is prefixed with its line number, dimmed.
+    pub line_numbers: bool,
+}
+
+impl RenderOptions {
+    /// Color is disabled by `--no-color`, the `NO_COLOR` convention, `CLAIPPY_NO_COLOR`, or stdout not being
+    /// a terminal. JSON output is enabled by `--json` or `CLAIPPY_OUTPUT=json`, and raw output by `--raw` or
+    /// `CLAIPPY_OUTPUT=raw`. The width is `--width`, then `COLUMNS`, then the terminal's width.
+    pub fn create(
+        no_color: bool,
+        json: bool,
+        raw: bool,
+        theme: Option<String>,
+        thinking: bool,
+        artifacts: ArtifactLayout,
+        width: Option<u16>,
+    ) -> RenderOptions {
+        let interactive = io::stdout().is_terminal();
+        let env_set = |key| env::var_os(key).is_some_and(|value| !value.is_empty());
+        let color =
+            !no_color && !env_set("NO_COLOR") && !env_set("CLAIPPY_NO_COLOR") && interactive;
+        let output = env::var("CLAIPPY_OUTPUT").unwrap_or_default();
+        let json = json || output == "json";
+        RenderOptions {
+            color: color && !json,
+            interactive,
+            json,
+            raw: raw || output == "raw",
+            theme,
+            thinking,
+            artifacts,
+            width: width
+                .or_else(|| env::var("COLUMNS").ok()?.parse().ok())
+                .filter(|width| *width > 0)
+                .unwrap_or_else(|| terminal_size().0),
+        }
+    }
+
+    pub fn skin(&self) -> MadSkin {
+        if self.color {
+            MadSkin::default()
+        } else {
+            MadSkin::no_style()
+        }
+    }
+
+    /// Formats markdown wrapped to the output width. Each line, including the last, ends with a newline. When
+    /// output is styled, fenced code blocks in a language with a known syntax are highlighted like artifacts,
+    /// since termimad styles all code alike.
+    fn markdown(&self, skin: &MadSkin, text: &str) -> String {
+        if !self.color || !text.contains("```") {
+            return self.plain_markdown(skin, text);
+        }
+        let fence_regex = FENCE.get_or_init(|| {
+            Regex::new(r"(?m)^[ \t]*```[ \t]*([\w+#.-]+)[^\n]*\n((?s:.*?))^[ \t]*```[ \t]*$")
+                .unwrap()
+        });
+
+        let mut formatted = String::new();
+        let mut last_end = 0;
+        for cap in fence_regex.captures_iter(text) {
+            let Some(syntax) = find_syntax(syntax_set(), &cap[1]) else {
+                continue;
+            };
+            let fence = cap.get(0).unwrap();
+
+            // termimad drops trailing blank lines, which separate the text from the code
+            let before = &text[last_end..fence.start()];
+            let blank_lines = before
+                .lines()
+                .rev()
+                .take_while(|l| l.trim().is_empty())
+                .count();
+            let before_lines: Vec<&str> = before.lines().collect();
+            let before = before_lines[..before_lines.len() - blank_lines].join("\n");
+            if !before.is_empty() {
+                formatted.push_str(&self.plain_markdown(skin, &before));
+            }
+            formatted.push_str(&"\n".repeat(blank_lines));
+
+            let mut h = HighlightLines::new(syntax, theme(self));
+            for line in LinesWithEndings::from(&cap[2]) {
+                formatted.push_str(&highlight_line(&mut h, line, self.width));
+            }
+            formatted.push_str("\x1b[0m"); // clear syntax, not handled by library
+
+            // The closing fence's line ending
+            last_end = fence.end();
+            if text[last_end..].starts_with('\n') {
+                last_end += 1;
+            }
+        }
+
+        let rest = &text[last_end..];
+        if !rest.is_empty() {
+            formatted.push_str(&self.plain_markdown(skin, rest));
+        }
+        formatted
+    }
+
+    fn plain_markdown(&self, skin: &MadSkin, text: &str) -> String {
+        skin.text(text, Some(usize::from(self.width))).to_string()
+    }
+}
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+// Loading syntaxes and themes is slow, so they're loaded once, when first needed, and reused across messages.
+static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+fn syntax_set() -> &'static SyntaxSet {
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme(render: &RenderOptions) -> &'static Theme {
+    THEME.get_or_init(|| load_theme(render.theme.as_deref()))
+}
+
+/// Loads the named theme, which is either one of syntect's default themes or a path to a `.tmTheme` file.
+/// Falls back to the default theme if it can't be found.
+fn load_theme(name: Option<&str>) -> Theme {
+    let mut themes = ThemeSet::load_defaults().themes;
+    if let Some(theme) = name.and_then(|name| themes.remove(name)) {
+        return theme;
+    }
+    let default_theme = themes.remove(DEFAULT_THEME).expect("default theme exists");
+
+    let Some(name) = name else {
+        return default_theme;
+    };
+    if name.ends_with(".tmTheme") {
+        match ThemeSet::get_theme(name) {
+            Ok(theme) => return theme,
+            Err(e) => eprintln!("Warning: could not load theme {name}: {e}"),
+        }
+    } else {
+        let mut available: Vec<String> = themes.into_keys().collect();
+        available.push(DEFAULT_THEME.to_owned());
+        available.sort();
+        eprintln!(
+            "Warning: unknown theme {name}, expected one of: {}",
+            available.join(", ")
+        );
+    }
+    default_theme
+}
+
+// Regexes used for each message or printed line are compiled once, too.
+static FENCE: OnceLock<Regex> = OnceLock::new();
+static ARTIFACT: OnceLock<Regex> = OnceLock::new();
+static IDENTIFIER_ATTR: OnceLock<Regex> = OnceLock::new();
+static LANGUAGE_ATTR: OnceLock<Regex> = OnceLock::new();
+static ANSI_ESCAPE: OnceLock<Regex> = OnceLock::new();
+
+/// How many terminal rows a printed line occupies, since lines longer than the terminal width wrap.
+fn terminal_rows(printed: &str, term_width: u16) -> usize {
+    let ansi_escape = ANSI_ESCAPE.get_or_init(|| Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap());
+    let width = ansi_escape.replace_all(printed, "").width();
+    if term_width == 0 || width == 0 {
+        1
+    } else {
+        width.div_ceil(usize::from(term_width))
+    }
+}
+
+/// Prints output through a pager if it's longer than the terminal is tall: `$PAGER`, or `less -R` so that styling is
+/// kept. Output isn't paged unless it's styled for a terminal, and an empty `PAGER` turns paging off.
+pub fn maybe_page(render: &RenderOptions, content: &str) -> Result<()> {
+    let (_width, height) = terminal_size();
+    let rows: usize = content
+        .lines()
+        .map(|line| terminal_rows(line, render.width))
+        .sum();
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less -R".to_owned());
+    let mut pager_args = pager.split_whitespace();
+    let paged = match pager_args.next() {
+        Some(program) if render.interactive && render.color && rows >= usize::from(height) => {
+            Command::new(program)
+                .args(pager_args)
+                .env("LESS", env::var("LESS").unwrap_or_else(|_| "R".to_owned()))
+                .stdin(Stdio::piped())
+                .spawn()
+                .inspect_err(|e| log::warn!("Could not start pager {pager}: {e}"))
+                .ok()
+        }
+        _ => None,
+    };
+
+    let Some(mut child) = paged else {
+        print!("{content}");
+        return Ok(());
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closes its input if it's quit before reading everything
+        match stdin.write_all(content.as_bytes()) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn erase_last_n_lines_simple(n: usize) {
+    // A zero count would still move the cursor up one line
+    if n == 0 {
+        return;
+    }
+    // Move up N lines
+    print!("\x1b[{}A", n);
+    // Clear from cursor down
+    print!("\x1b[J");
+    // Flush stdout
+    std::io::stdout().flush().unwrap();
+}
+
+const CLAIPPY_ARTIFACT: &str = "ClaippyArtifact";
+const ARTIFACT_OPEN: &str = "<ClaippyArtifact";
+const ARTIFACT_CLOSE: &str = "</ClaippyArtifact>";
+
+/// Splits a response into Markdown and artifacts. This is the inverse of `RichMessage::as_message`, so the
+/// newline it writes inside each artifact tag, and the separator it writes between parts, are removed.
+pub fn parse_message_parts(full_content: String) -> Vec<MessageParts> {
+    let mut parts = Vec::new();
+    // Ideally use a real XML parser here instead
+    let artifact_regex = ARTIFACT.get_or_init(|| {
+        Regex::new(&format!(
+            r"(?ms)<{}\s*([^>]*?)>(.*?)</{}>",
+            CLAIPPY_ARTIFACT, CLAIPPY_ARTIFACT
+        ))
+        .unwrap()
+    });
+    let mut last_end = 0;
+
+    for cap in artifact_regex.captures_iter(&full_content) {
+        let start = cap.get(0).unwrap().start();
+        let end = cap.get(0).unwrap().end();
+
+        log::info!("Capture {start:?} {end:?} ");
+
+        // Add any text before the artifact as Markdown
+        let mut text = &full_content[last_end..start];
+        if last_end > 0 {
+            text = text.strip_prefix(PART_SEPARATOR).unwrap_or(text);
+        }
+        text = text.strip_suffix(PART_SEPARATOR).unwrap_or(text);
+        if !text.is_empty() {
+            parts.push(MessageParts::Markdown(text.to_string()));
+        }
+
+        let (identifier, language) = parse_artifact_attrs(cap.get(1).unwrap().as_str());
+        let content = cap.get(2).unwrap().as_str();
+        let content = content.strip_prefix('\n').unwrap_or(content);
+        let content = content.strip_suffix('\n').unwrap_or(content);
+
+        // Add the artifact
+        parts.push(MessageParts::Artifact {
+            identifier,
+            language,
+            content: content.to_string(),
+        });
+
+        last_end = end;
+    }
+
+    // Add any remaining text as Markdown
+    let mut text = &full_content[last_end..];
+    if last_end > 0 {
+        text = text.strip_prefix(PART_SEPARATOR).unwrap_or(text);
+    }
+    if !text.is_empty() {
+        parts.push(MessageParts::Markdown(text.to_string()));
+        log::info!("Emitted final markdown part")
+    }
+
+    parts
+}
+
+/// Parses the identifier and language from the attributes of an artifact's opening tag.
+fn parse_artifact_attrs(attrs: &str) -> (String, Option<String>) {
+    let identifier_regex =
+        IDENTIFIER_ATTR.get_or_init(|| Regex::new(r#"identifier="([^"]*)""#).unwrap());
+    let language_regex = LANGUAGE_ATTR.get_or_init(|| Regex::new(r#"language="([^"]*)""#).unwrap());
+
+    let identifier = identifier_regex
+        .captures(attrs)
+        .and_then(|c| c.get(1))
+        .map(|m| unescape_attr(m.as_str()))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let language = language_regex
+        .captures(attrs)
+        .and_then(|c| c.get(1))
+        .map(|m| unescape_attr(m.as_str()));
+
+    (identifier, language)
+}
+
+// Reverses the escaping `RichMessage::as_message` applies to attribute values.
+fn unescape_attr(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Finds the syntax for an artifact's language tag, which is often a short alias (`js`, `sh`) rather than
+/// syntect's name for the syntax (`JavaScript`, `Bourne Again Shell (bash)`).
+fn find_syntax<'a>(ps: &'a SyntaxSet, language: &str) -> Option<&'a SyntaxReference> {
+    // The default syntaxes have no TypeScript, so use JavaScript
+    let token = match extension_for_language(language) {
+        Some("ts") => "js",
+        Some(extension) => extension,
+        None => language,
+    };
+    // Searches by extension, then by case-insensitive name
+    ps.find_syntax_by_token(token)
+}
+
+/// A highlighter for an artifact's language, if it has one and output is styled.
+fn artifact_highlighter(
+    render: &RenderOptions,
+    language: Option<&str>,
+) -> Option<HighlightLines<'static>> {
+    let lang = language.filter(|_| render.color)?;
+    log::info!("Language: {}", lang);
+
+    match find_syntax(syntax_set(), lang) {
+        Some(syntax) => Some(HighlightLines::new(syntax, theme(render))),
+        None => {
+            log::warn!("No syntax found for language {}", lang);
+            None
+        }
+    }
+}
+
+fn artifact_intro(render: &RenderOptions, identifier: &str, language: Option<&str>) -> String {
+    if !render.artifacts.headers {
+        return String::new();
+    }
+    let artifact_intro = format!(
+        "[Artifact: {} ({})]\n",
+        identifier,
+        language.unwrap_or("None")
+    );
+    if render.color {
+        format!("{}", artifact_intro.dim())
+    } else {
+        artifact_intro
+    }
+}
+
+/// The gutter before an artifact's line, if lines are numbered. Only the dim attribute is reset after it, so
+/// the background color that the highlighted line fills to the terminal width with is kept.
+fn line_number(render: &RenderOptions, number: usize) -> String {
+    match (render.artifacts.line_numbers, render.color) {
+        (false, _) => String::new(),
+        (true, true) => format!("\x1b[2m{number:>4}\x1b[22m  "),
+        (true, false) => format!("{number:>4}  "),
+    }
+}
+
+/// Highlights a single line, including its line ending.
+fn highlight_line(h: &mut HighlightLines, line: &str, term_width: u16) -> String {
+    let ranges: Vec<(Style, &str)> = h.highlight_line(line, syntax_set()).unwrap();
+    let escaped = as_24_bit_terminal_escaped(&ranges[..], true);
+    // Sets the line length to the term with, which allows the background formatting to
+    // extend to this length, which looks nicer.
+    format!("\x1b[{}X{}", term_width, escaped)
+}
+
+/// An artifact's content on its own, highlighted when color is enabled.
+pub fn format_artifact_content(
+    render: &RenderOptions,
+    language: Option<&str>,
+    content: &str,
+) -> String {
+    let Some(mut h) = artifact_highlighter(render, language) else {
+        return content.to_owned();
+    };
+    let mut formatted: String = LinesWithEndings::from(content)
+        .map(|line| highlight_line(&mut h, line, render.width))
+        .collect();
+    formatted.push_str("\x1b[0m"); // clear syntax, not handled by library
+    formatted
+}
+
+pub fn format_message(
+    render: &RenderOptions,
+    skin: &MadSkin,
+    full_message: &[MessageParts],
+) -> String {
+    let mut formatted = String::new();
+
+    for part in full_message {
+        match part {
+            MessageParts::Markdown(text) => {
+                formatted.push_str(&render.markdown(skin, text));
+            }
+            MessageParts::Thinking(text) if render.thinking => {
+                formatted.push_str(&format_dimmed(render, text.trim_end()));
+                formatted.push_str("\n\n");
+            }
+            MessageParts::Thinking(_) => {}
+            MessageParts::Image(image) => {
+                formatted.push_str(&format_dimmed(render, &format!("[Image: {}]", image.path)));
+                formatted.push_str("\n\n");
+            }
+            MessageParts::Artifact {
+                identifier,
+                language,
+                content,
+            } => {
+                formatted.push_str(&artifact_intro(render, identifier, language.as_deref()));
+
+                // Without a highlighter (no language specified, no syntax found, or color disabled), lines are
+                // printed as they are
+                let mut highlighter = artifact_highlighter(render, language.as_deref());
+                for (index, line) in LinesWithEndings::from(content).enumerate() {
+                    formatted.push_str(&line_number(render, index + 1));
+                    match &mut highlighter {
+                        Some(h) => formatted.push_str(&highlight_line(h, line, render.width)),
+                        None => formatted.push_str(line),
+                    }
+                }
+                if highlighter.is_some() {
+                    formatted.push_str("\x1b[0m"); // clear syntax, not handled by library
+                }
+
+                formatted.push('\n');
+            }
+        }
+    }
+
+    formatted
+}
+
+fn format_dimmed(render: &RenderOptions, text: &str) -> String {
+    if render.color {
+        text.dim().to_string()
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Renders a conversation as GitHub-flavored markdown for sharing, rather than for the terminal: no styling,
+/// artifacts as fenced code blocks, and the context sent with each message listed rather than inlined.
+pub fn export_markdown(conversation: &Conversation) -> String {
+    let mut exported = format!("# {}\n\n", conversation.id);
+    for message in &conversation.messages {
+        exported.push_str(&format!("## {}\n\n", message.role()));
+        if !message.context().is_empty() {
+            let context = message
+                .context()
+                .iter()
+                .map(|context| format!("`{context}`"))
+                .collect::<Vec<String>>()
+                .join(", ");
+            exported.push_str(&format!("> Context: {context}\n\n"));
+        }
+
+        for part in message.parts_without_context() {
+            match part {
+                MessageParts::Markdown(text) => {
+                    exported.push_str(text.trim());
+                    exported.push_str("\n\n");
+                }
+                MessageParts::Thinking(text) => {
+                    exported.push_str(&format!(
+                        "<details>\n<summary>Thinking</summary>\n\n{}\n\n</details>\n\n",
+                        text.trim()
+                    ));
+                }
+                MessageParts::Image(image) => {
+                    exported.push_str(&format!("*Image: `{}`*\n\n", image.path));
+                }
+                MessageParts::Artifact {
+                    identifier,
+                    language,
+                    content,
+                } => {
+                    let fence = code_fence(content);
+                    exported.push_str(&format!(
+                        "**`{identifier}`**\n\n{fence}{}\n{}\n{fence}\n\n",
+                        language.as_deref().unwrap_or_default(),
+                        content.trim_end_matches('\n')
+                    ));
+                }
+            }
+        }
+    }
+    exported.truncate(exported.trim_end().len());
+    exported.push('\n');
+    exported
+}
+
+// A backtick fence longer than any run of backticks in the content, so the content can't close it early.
+fn code_fence(content: &str) -> String {
+    let longest_run = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    "`".repeat(longest_run.max(2) + 1)
+}
+
+enum StreamState {
+    Markdown {
+        // Completed lines of the current block, which haven't been formatted yet
+        block: Vec<String>,
+        // How many terminal rows the current block's unformatted lines take up
+        printed_rows: usize,
+        in_fence: bool,
+    },
+    Artifact {
+        highlighter: Option<HighlightLines<'static>>,
+        // The number of the next line of the artifact
+        line: usize,
+    },
+}
+
+impl StreamState {
+    fn markdown() -> StreamState {
+        StreamState::Markdown {
+            block: Vec::new(),
+            printed_rows: 0,
+            in_fence: false,
+        }
+    }
+}
+
+/// Renders a response as it is streamed from the model. In raw mode, the text is printed exactly as it arrives.
+/// Otherwise, markdown is printed a line at a time, and each block (paragraph, list, table, code fence) is repainted
+/// with full formatting once it is complete. Artifacts are highlighted line by line as they arrive.
+pub struct StreamRenderer<'a> {
+    render: &'a RenderOptions,
+    skin: MadSkin,
+    term_width: u16,
+    // Text received since the last newline
+    pending: String,
+    state: StreamState,
+    // Whether the model's reasoning is being printed, so the response needs separating from it
+    thinking: bool,
+}
+
+impl<'a> StreamRenderer<'a> {
+    pub fn new(render: &'a RenderOptions) -> Self {
+        StreamRenderer {
+            render,
+            skin: render.skin(),
+            term_width: render.width,
+            pending: String::new(),
+            state: StreamState::markdown(),
+            thinking: false,
+        }
+    }
+
+    /// Prepares to render a continuation of `previous`. If it stopped partway through an artifact, the
+    /// continuation is rendered as the rest of that artifact.
+    pub fn resume_after(&mut self, previous: &str) {
+        let Some(start) = previous.rfind(ARTIFACT_OPEN) else {
+            return;
+        };
+        let tag = &previous[start + ARTIFACT_OPEN.len()..];
+        if let (Some(end), None) = (tag.find('>'), tag.find(ARTIFACT_CLOSE)) {
+            self.start_artifact(&tag[..end]);
+            // The newline after the opening tag doesn't start a line, but the continuation may finish one
+            if let StreamState::Artifact { line, .. } = &mut self.state {
+                *line = tag[end..].matches('\n').count().max(1);
+            }
+        }
+    }
+
+    /// Prints the model's reasoning as it streams, dimmed, if it is shown. It comes before the response.
+    pub fn push_thinking(&mut self, text: &str) -> Result<()> {
+        if !self.render.thinking {
+            return Ok(());
+        }
+        self.thinking = true;
+        print!("{}", format_dimmed(self.render, text));
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, text: &str) -> Result<()> {
+        if mem::take(&mut self.thinking) {
+            println!("\n");
+        }
+        if self.render.raw {
+            print!("{text}");
+            io::stdout().flush()?;
+            // Only kept so that `finish` knows whether the response ended with a newline
+            self.pending.replace_range(.., text);
+            return Ok(());
+        }
+        self.pending.push_str(text);
+        while let Some(newline) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=newline).collect();
+            self.line(line.trim_end_matches('\n'));
+        }
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    /// Renders any remaining partial line, and closes an unterminated artifact.
+    pub fn finish(mut self) -> Result<()> {
+        if self.render.raw {
+            if !self.pending.is_empty() && !self.pending.ends_with('\n') {
+                println!();
+            }
+            return Ok(());
+        }
+        if !self.pending.is_empty() {
+            let line = mem::take(&mut self.pending);
+            self.line(&line);
+        }
+        match self.state {
+            StreamState::Markdown { .. } => self.flush_block(),
+            StreamState::Artifact { .. } => self.end_artifact(),
+        }
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) {
+        match self.state {
+            StreamState::Markdown { .. } => {
+                let open_tag = line.find(ARTIFACT_OPEN).and_then(|start| {
+                    line[start..]
+                        .find('>')
+                        .map(|tag_len| (start, start + tag_len))
+                });
+                if let Some((start, end)) = open_tag {
+                    if !line[..start].trim().is_empty() {
+                        self.markdown_line(&line[..start]);
+                    }
+                    self.flush_block();
+                    self.start_artifact(&line[start + ARTIFACT_OPEN.len()..end]);
+                    if end + 1 < line.len() {
+                        self.line(&line[end + 1..]);
+                    }
+                } else {
+                    self.markdown_line(line);
+                }
+            }
+            StreamState::Artifact { .. } => {
+                if let Some(start) = line.find(ARTIFACT_CLOSE) {
+                    if start > 0 {
+                        self.artifact_line(&line[..start]);
+                    }
+                    self.end_artifact();
+                    let rest = &line[start + ARTIFACT_CLOSE.len()..];
+                    if !rest.trim().is_empty() {
+                        self.line(rest);
+                    }
+                } else {
+                    self.artifact_line(line);
+                }
+            }
+        }
+    }
+
+    fn markdown_line(&mut self, line: &str) {
+        let StreamState::Markdown {
+            block,
+            printed_rows,
+            in_fence,
+        } = &mut self.state
+        else {
+            return;
+        };
+
+        if line.trim_start().starts_with("```") {
+            *in_fence = !*in_fence;
+        }
+        // Each line is wrapped as the block will be once it's complete, so the repaint doesn't reflow it
+        if self.render.interactive {
+            let formatted = self.render.markdown(&self.skin, line);
+            if formatted.is_empty() {
+                println!();
+                *printed_rows += 1;
+            } else {
+                print!("{formatted}");
+                *printed_rows += formatted
+                    .lines()
+                    .map(|line| terminal_rows(line, self.term_width))
+                    .sum::<usize>();
+            }
+        }
+        block.push(line.to_owned());
+
+        if line.trim().is_empty() && !*in_fence {
+            self.flush_block();
+        }
+    }
+
+    // Replaces the unformatted lines of the current block with the fully formatted block
+    fn flush_block(&mut self) {
+        let StreamState::Markdown {
+            block,
+            printed_rows,
+            in_fence,
+        } = &mut self.state
+        else {
+            return;
+        };
+
+        if block.is_empty() {
+            return;
+        }
+        if self.render.interactive {
+            erase_last_n_lines_simple(*printed_rows);
+        }
+        // termimad drops trailing blank lines, which separate this block from the next
+        let blank_lines = block
+            .iter()
+            .rev()
+            .take_while(|l| l.trim().is_empty())
+            .count();
+        let text = block[..block.len() - blank_lines].join("\n");
+        if !text.is_empty() {
+            print!("{}", self.render.markdown(&self.skin, &text));
+        }
+        for _ in 0..blank_lines {
+            println!();
+        }
+
+        block.clear();
+        *printed_rows = 0;
+        *in_fence = false;
+    }
+
+    fn start_artifact(&mut self, attrs: &str) {
+        let (identifier, language) = parse_artifact_attrs(attrs);
+        print!(
+            "{}",
+            artifact_intro(self.render, &identifier, language.as_deref())
+        );
+        self.state = StreamState::Artifact {
+            highlighter: artifact_highlighter(self.render, language.as_deref()),
+            line: 1,
+        };
+    }
+
+    fn artifact_line(&mut self, line: &str) {
+        let StreamState::Artifact {
+            highlighter,
+            line: number,
+        } = &mut self.state
+        else {
+            return;
+        };
+
+        print!("{}", line_number(self.render, *number));
+        *number += 1;
+        match highlighter {
+            Some(h) => print!(
+                "{}",
+                highlight_line(h, &format!("{line}\n"), self.term_width)
+            ),
+            None => println!("{line}"),
+        }
+    }
+
+    fn end_artifact(&mut self) {
+        if let StreamState::Artifact {
+            highlighter: Some(_),
+            ..
+        } = self.state
+        {
+            print!("\x1b[0m"); // clear syntax, not handled by library
+        }
+        self.state = StreamState::markdown();
+    }
+}
+
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Animates a "thinking" indicator with the elapsed time on a background thread, while waiting for the first
+/// chunk of a response. The indicator is erased when the spinner is dropped.
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    /// Starts a spinner, unless output isn't to a terminal or is JSON.
+    pub fn start(render: &RenderOptions) -> Option<Spinner> {
+        if !render.interactive || render.json {
+            return None;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let started = Instant::now();
+            let mut stdout = io::stdout();
+            for frame in SPINNER_FRAMES.iter().cycle() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let elapsed = started.elapsed().as_secs_f32();
+                let _ = write!(stdout, "\r{frame} Thinking... {elapsed:.1}s");
+                let _ = stdout.flush();
+                thread::sleep(Duration::from_millis(100));
+            }
+            // Erase the indicator so the response is printed where it was
+            let _ = write!(stdout, "\r\x1b[2K");
+            let _ = stdout.flush();
+        });
+        Some(Spinner {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a response into parts and stores it, as a response to a query is.
+    fn store_response(response: &str) -> Conversation {
+        let mut conversation = Conversation::empty("test");
+        conversation.add_assistant_message(parse_message_parts(response.to_owned()));
+        conversation
+    }
+
+    #[test]
+    fn artifacts_round_trip_through_as_message() {
+        let response = "Here's the script:\n\n\
+            <ClaippyArtifact identifier=\"hello\" language=\"python\">\nprint(\"hello\")\n</ClaippyArtifact>\n\n\
+            And a note:\n\n\
+            <ClaippyArtifact identifier=\"a &amp; b\">\nsome notes\n</ClaippyArtifact>\n\n\
+            That's all.";
+        let conversation = store_response(response);
+        let message = &conversation.messages[0];
+        assert_eq!(
+            message.parts(),
+            [
+                MessageParts::Markdown("Here's the script:".to_owned()),
+                MessageParts::Artifact {
+                    identifier: "hello".to_owned(),
+                    language: Some("python".to_owned()),
+                    content: "print(\"hello\")".to_owned(),
+                },
+                MessageParts::Markdown("And a note:".to_owned()),
+                MessageParts::Artifact {
+                    identifier: "a & b".to_owned(),
+                    language: None,
+                    content: "some notes".to_owned(),
+                },
+                MessageParts::Markdown("That's all.".to_owned()),
+            ]
+        );
+        assert_eq!(message.as_message().content, response);
+    }
+
+    #[test]
+    fn unterminated_artifact_round_trips_as_markdown() {
+        let response = "Done:\n\n\
+            <ClaippyArtifact identifier=\"done\">\nfinished\n</ClaippyArtifact>\n\n\
+            Then the response was cut off:\n\n\
+            <ClaippyArtifact identifier=\"cut\" language=\"rust\">\nfn main() {";
+        let conversation = store_response(response);
+        let message = &conversation.messages[0];
+        assert_eq!(
+            message.parts(),
+            [
+                MessageParts::Markdown("Done:".to_owned()),
+                MessageParts::Artifact {
+                    identifier: "done".to_owned(),
+                    language: None,
+                    content: "finished".to_owned(),
+                },
+                MessageParts::Markdown(
+                    "Then the response was cut off:\n\n\
+                     <ClaippyArtifact identifier=\"cut\" language=\"rust\">\nfn main() {"
+                        .to_owned()
+                ),
+            ]
+        );
+        assert_eq!(message.as_message().content, response);
+    }
+
+    // A small xorshift generator, so that the generated messages are the same on every run.
+    struct Generator(u64);
+
+    impl Generator {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len())]
+        }
+
+        fn text(&mut self, pieces: &[&str], max_len: usize) -> String {
+            (0..=self.below(max_len))
+                .map(|_| self.pick(pieces))
+                .collect()
+        }
+    }
+
+    // Pieces of text that could be mistaken for markup. Markdown can't contain an artifact tag, but content can
+    // contain anything except the closing tag.
+    const TEXT: &[&str] = &[
+        "word",
+        " ",
+        "\n",
+        "\n\n",
+        "<",
+        ">",
+        "\"",
+        "&",
+        "&amp;",
+        "&quot;",
+        "&lt;",
+        "<div>",
+        "</div>",
+        "<Claippy>",
+        "</ClaippyContext>",
+        "```rust",
+        "=",
+        "/",
+    ];
+    const CONTENT: &[&str] = &[
+        "fn main() {}",
+        " ",
+        "\n",
+        "\n\n",
+        "<",
+        ">",
+        "\"",
+        "&",
+        "&gt;",
+        "<ClaippyArtifact identifier=\"inner\">",
+        "</Claippy>",
+        "identifier=\"x\"",
+    ];
+    const ATTR: &[&str] = &[
+        "a", "-", " ", "<", ">", "\"", "&", "&amp;", "&quot;", "/", "=", "'",
+    ];
+
+    fn generate_parts(generator: &mut Generator) -> Vec<MessageParts> {
+        let mut parts = Vec::new();
+        for _ in 0..=generator.below(5) {
+            // Adjacent Markdown parts would be joined into one
+            let after_markdown = matches!(parts.last(), Some(MessageParts::Markdown(_)));
+            if after_markdown || generator.below(2) == 0 {
+                parts.push(MessageParts::Artifact {
+                    identifier: generator.text(ATTR, 6),
+                    language: match generator.below(2) {
+                        0 => None,
+                        _ => Some(generator.text(ATTR, 4)),
+                    },
+                    content: match generator.below(5) {
+                        0 => String::new(),
+                        _ => generator.text(CONTENT, 8),
+                    },
+                });
+            } else {
+                parts.push(MessageParts::Markdown(generator.text(TEXT, 8)));
+            }
+        }
+        parts
+    }
+
+    #[test]
+    fn generated_parts_round_trip_through_as_message() {
+        let mut generator = Generator(0x9e37_79b9_7f4a_7c15);
+        for case in 0..2000 {
+            let parts = generate_parts(&mut generator);
+            let mut conversation = Conversation::empty("test");
+            conversation.add_assistant_message(parts);
+            let sent = conversation.messages[0].as_message().content;
+            assert_eq!(
+                parse_message_parts(sent.clone()),
+                conversation.messages[0].parts(),
+                "case {case}: {sent:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn artifact_lines_are_numbered_in_a_gutter() {
+        let mut render = RenderOptions {
+            color: false,
+            interactive: false,
+            json: false,
+            raw: false,
+            theme: None,
+            thinking: false,
+            artifacts: ArtifactLayout {
+                headers: false,
+                line_numbers: true,
+            },
+            width: 80,
+        };
+        let artifact = [MessageParts::Artifact {
+            identifier: "notes".to_owned(),
+            language: None,
+            content: "first\nsecond\n".to_owned(),
+        }];
+        let format = |render: &RenderOptions| format_message(render, &render.skin(), &artifact);
+
+        assert_eq!(format(&render), "   1  first\n   2  second\n\n");
+        render.color = true;
+        assert_eq!(
+            format(&render),
+            "\x1b[2m   1\x1b[22m  first\n\x1b[2m   2\x1b[22m  second\n\n"
+        );
+        render.artifacts.line_numbers = false;
+        assert_eq!(format(&render), "first\nsecond\n\n");
+    }
+}
@@ -1,27 +1,45 @@
-use std::{borrow::Cow, io::Cursor};
+use std::{
+    borrow::Cow,
+    env, fs,
+    io::Cursor,
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::model::{self, workspace_files};
 
 use colored::Colorize;
 use rustyline::{
     completion::FilenameCompleter,
     error::ReadlineError,
     highlight::{Highlighter, MatchingBracketHighlighter},
-    hint::HistoryHinter,
+    hint::{Hinter, HistoryHinter},
     history::DefaultHistory,
-    validate::MatchingBracketValidator,
-    Completer, ConditionalEventHandler, Editor, EventHandler, Helper, Hinter, KeyEvent, Validator,
+    validate::{MatchingBracketValidator, ValidationContext, ValidationResult, Validator},
+    Cmd, Completer, ConditionalEventHandler, Context, Editor, EventHandler, Helper, KeyCode,
+    KeyEvent, Modifiers, Movement,
 };
 use skim::prelude::*;
 
-#[derive(Helper, Completer, Hinter, Validator)]
+// Ends a line to continue the input on the next line.
+const LINE_CONTINUATION: &str = "\\";
+const MULTILINE_HINT: &str =
+    "[multi-line: Enter adds a line, an empty line submits, Alt-M to exit]";
+
+#[derive(Helper, Completer)]
 pub struct ReplHelper {
     #[rustyline(Completer)]
     completer: FilenameCompleter,
     highlighter: MatchingBracketHighlighter,
-    #[rustyline(Validator)]
     validator: MatchingBracketValidator,
-    #[rustyline(Hinter)]
     hinter: HistoryHinter,
     colored_prompt: String,
+    // In multi-line mode, Enter starts a new line, and the input is only submitted by an empty line.
+    multiline: Arc<AtomicBool>,
 }
 
 impl ReplHelper {
@@ -32,7 +50,37 @@ impl ReplHelper {
             validator: MatchingBracketValidator::new(),
             hinter: HistoryHinter::new(),
             colored_prompt: prompt.to_owned(),
+            multiline: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if self.multiline.load(Ordering::SeqCst) {
+            // Submitting an empty line ends the input. An entirely empty input is submitted as is.
+            return Ok(if input.is_empty() || input.ends_with('\n') {
+                ValidationResult::Valid(None)
+            } else {
+                ValidationResult::Incomplete
+            });
+        }
+        if input.ends_with(LINE_CONTINUATION) {
+            return Ok(ValidationResult::Incomplete);
+        }
+        self.validator.validate(ctx)
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if line.is_empty() && self.multiline.load(Ordering::SeqCst) {
+            return Some(MULTILINE_HINT.to_owned());
         }
+        self.hinter.hint(line, pos, ctx)
     }
 }
 
@@ -58,18 +106,172 @@ impl Highlighter for ReplHelper {
     }
 }
 
-pub fn make_readline(prompt: &str) -> Result<Editor<ReplHelper, DefaultHistory>, ReadlineError> {
+/// Adds files to the conversation's context, returning a description of what was added.
+pub type AddContext = dyn Fn(Vec<String>) -> model::Result<String> + Send + Sync;
+
+pub fn make_readline(
+    prompt: &str,
+    add_context: Box<AddContext>,
+) -> Result<Editor<ReplHelper, DefaultHistory>, ReadlineError> {
     let mut rl: Editor<ReplHelper, DefaultHistory> = Editor::new()?;
     let helper = ReplHelper::new(prompt);
+    let multiline = Arc::clone(&helper.multiline);
     rl.set_helper(Some(helper));
     rl.bind_sequence(
         KeyEvent::ctrl('j'),
         EventHandler::Conditional(Box::new(SkimInserter)),
     );
+    rl.bind_sequence(
+        KeyEvent::ctrl('f'),
+        EventHandler::Conditional(Box::new(SkimContextAdder { add_context })),
+    );
+    // Alt-Enter always starts a new line, and Alt-M toggles multi-line mode.
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Enter, Modifiers::ALT),
+        EventHandler::Simple(Cmd::Newline),
+    );
+    rl.bind_sequence(
+        KeyEvent::alt('m'),
+        EventHandler::Conditional(Box::new(MultilineToggle { multiline })),
+    );
+    // Ctrl-X Ctrl-E composes the input in $EDITOR, as in bash
+    rl.bind_sequence(
+        rustyline::Event::KeySeq(vec![KeyEvent::ctrl('x'), KeyEvent::ctrl('e')]),
+        EventHandler::Conditional(Box::new(EditorInserter)),
+    );
 
     Ok(rl)
 }
 
+/// The text of REPL input, where a line ending in a continuation backslash is joined to the next line.
+pub fn join_continued_lines(input: &str) -> String {
+    input.replace(&format!("{LINE_CONTINUATION}\n"), "\n")
+}
+
+struct MultilineToggle {
+    multiline: Arc<AtomicBool>,
+}
+
+impl ConditionalEventHandler for MultilineToggle {
+    fn handle(
+        &self,
+        _evt: &rustyline::Event,
+        _n: rustyline::RepeatCount,
+        _positive: bool,
+        _ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        self.multiline.fetch_xor(true, Ordering::SeqCst);
+        // Repaint so the multi-line hint is shown or hidden
+        Some(Cmd::Repaint)
+    }
+}
+
+/// Replaces the current input with the same input edited in `$EDITOR`.
+struct EditorInserter;
+
+impl ConditionalEventHandler for EditorInserter {
+    fn handle(
+        &self,
+        _evt: &rustyline::Event,
+        _n: rustyline::RepeatCount,
+        _positive: bool,
+        ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        let path = env::temp_dir().join(format!("claippy-prompt-{}.md", process::id()));
+        match edit_in_editor(ctx.line(), &path) {
+            Ok(edited) => Some(Cmd::Replace(
+                Movement::WholeBuffer,
+                Some(edited.trim_end().to_owned()),
+            )),
+            Err(e) => {
+                eprintln!("\nWarning: could not edit the input: {e}");
+                Some(Cmd::Repaint)
+            }
+        }
+    }
+}
+
+/// Opens `$EDITOR` (falling back to `vi`) on a file at `path` containing `initial`, and returns the edited text.
+pub fn edit_in_editor(initial: &str, path: &Path) -> model::Result<String> {
+    fs::write(path, initial)?;
+
+    // EDITOR may include arguments, e.g. `code --wait`.
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args.next().ok_or("EDITOR is empty")?;
+    let status = process::Command::new(program)
+        .args(editor_args)
+        .arg(path)
+        .status()
+        .map_err(|e| format!("Could not run editor {editor}: {e}"))?;
+
+    let edited = fs::read_to_string(path);
+    fs::remove_file(path)?;
+    if !status.success() {
+        return Err(format!("Editor {editor} exited with {status}").into());
+    }
+    Ok(edited?)
+}
+
+/// The workspace files offered for selection, newline-separated.
+fn get_files_for_selection() -> String {
+    workspace_files(Path::new(".")).join("\n")
+}
+
+/// Whether `program` can be run, either as a path or by searching `PATH`.
+fn is_executable(program: &str) -> bool {
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        return Path::new(program).is_file();
+    }
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+/// The command skim runs to preview the highlighted file, with `{}` replaced by its path. Taken from
+/// `CLAIPPY_SKIM_PREVIEW` if its program can be found, otherwise `bat` if it is installed, otherwise `cat`.
+fn preview_command() -> String {
+    if let Ok(command) = env::var("CLAIPPY_SKIM_PREVIEW") {
+        match command.split_whitespace().next() {
+            Some(program) if is_executable(program) => return command,
+            _ => log::warn!("Preview command {command:?} not found, using the default"),
+        }
+    }
+    if is_executable("bat") {
+        "bat --color=always --style=numbers {}".to_owned()
+    } else {
+        "cat {}".to_owned()
+    }
+}
+
+/// Lets the user pick workspace files with skim, returning their paths.
+fn select_files() -> Vec<String> {
+    let preview = preview_command();
+    let options = SkimOptionsBuilder::default()
+        .multi(true)
+        .preview(Some(&preview))
+        .build()
+        .unwrap();
+
+    let input = get_files_for_selection();
+
+    // `SkimItemReader` is a helper to turn any `BufRead` into a stream of `SkimItem`
+    // `SkimItem` was implemented for `AsRef<str>` by default
+    let item_reader = SkimItemReader::default();
+    let items = item_reader.of_bufread(Cursor::new(input));
+
+    // `run_with` would read and show items from the stream
+    let selected_items = Skim::run_with(&options, Some(items))
+        .filter(|out| !out.is_abort)
+        .map(|out| out.selected_items)
+        .unwrap_or_default();
+
+    selected_items
+        .iter()
+        .map(|i| i.output().into_owned())
+        .collect()
+}
+
+/// Inserts the paths of the files picked with skim into the input.
 struct SkimInserter;
 
 impl ConditionalEventHandler for SkimInserter {
@@ -80,27 +282,28 @@ impl ConditionalEventHandler for SkimInserter {
         _positive: bool,
         _ctx: &rustyline::EventContext,
     ) -> Option<rustyline::Cmd> {
-        let options = SkimOptionsBuilder::default().multi(true).build().unwrap();
-
-        let input = "aaaaa\nbbbb\nccc".to_string();
-
-        // `SkimItemReader` is a helper to turn any `BufRead` into a stream of `SkimItem`
-        // `SkimItem` was implemented for `AsRef<str>` by default
-        let item_reader = SkimItemReader::default();
-        let items = item_reader.of_bufread(Cursor::new(input));
-
-        // `run_with` would read and show items from the stream
-        let selected_items = Skim::run_with(&options, Some(items))
-            .map(|out| out.selected_items)
-            .unwrap_or_else(|| Vec::new());
-
-        Some(rustyline::Cmd::Insert(
-            1,
-            selected_items
-                .iter()
-                .map(|i| i.output())
-                .collect::<Vec<Cow<str>>>()
-                .join(","),
-        ))
+        Some(rustyline::Cmd::Insert(1, select_files().join(",")))
+    }
+}
+
+/// Adds the files picked with skim to the context, leaving the input as it was.
+struct SkimContextAdder {
+    add_context: Box<AddContext>,
+}
+
+impl ConditionalEventHandler for SkimContextAdder {
+    fn handle(
+        &self,
+        _evt: &rustyline::Event,
+        _n: rustyline::RepeatCount,
+        _positive: bool,
+        _ctx: &rustyline::EventContext,
+    ) -> Option<rustyline::Cmd> {
+        let files = select_files();
+        if !files.is_empty() {
+            let message = (self.add_context)(files).unwrap_or_else(|e| format!("Error: {e}"));
+            println!("\n{message}");
+        }
+        Some(Cmd::Repaint)
     }
 }